@@ -0,0 +1,1054 @@
+use util::*;
+use state::*;
+
+/// Bits which always read as 1 for each register from NR10 (0xff10) to 0xff2f, write only bits
+/// and unused registers read back as set.
+const READ_MASKS: [u8; 0x20] = [0x80, 0x3f, 0x00, 0xff, 0xbf, 0xff, 0x3f, 0x00, 0xff, 0xbf, 0x7f,
+                                0xff, 0x9f, 0xff, 0xbf, 0xff, 0xff, 0x00, 0x00, 0xbf, 0x00, 0x00,
+                                0x70, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+/// Counts down the remaining length of a sound, disabling its channel once it expires if length
+/// is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub counter: u16,
+    /// 64, or 256 for the wave channel
+    pub max: u16,
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    /// Loads the counter from the length field of NRx1.
+    pub fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    /// Clocked at 256 Hz by the frame sequencer, returns true if the counter expired.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    /// Sets the length enable bit from a write to NRx4, returns true if the counter expired. When
+    /// the next frame sequencer step does not clock length, enabling length clocks it once extra.
+    pub fn set_enabled(&mut self, enabled: bool, length_quirk: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enabled;
+        if length_quirk && !was_enabled && enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
+        } else {
+            false
+        }
+    }
+
+    /// An expired counter is reloaded with the full length on trigger, minus the extra clock if
+    /// the next frame sequencer step does not clock length.
+    pub fn trigger(&mut self, length_quirk: bool) {
+        if self.counter == 0 {
+            self.counter = self.max;
+            if self.enabled && length_quirk {
+                self.counter -= 1;
+            }
+        }
+    }
+}
+
+/// The volume envelope of the square and noise channels, NRx2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Envelope {
+    pub initial_volume: u8,
+    pub increase: bool,
+    pub period: u8,
+
+    pub volume: u8,
+    pub timer: u8,
+}
+
+impl Envelope {
+    pub fn get_register(&self) -> u8 {
+        self.initial_volume << 4 | (self.increase as u8) << 3 | self.period
+    }
+
+    pub fn set_register(&mut self, n: u8) {
+        self.initial_volume = high_nibble(n);
+        self.increase = get_bit(n, 3);
+        self.period = n & 0x07;
+    }
+
+    /// The channel's DAC is powered whenever the upper 5 bits of NRx2 are not all 0.
+    pub fn dac_enabled(&self) -> bool {
+        self.get_register() & 0xf8 != 0
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    /// Clocked at 64 Hz by the frame sequencer, a period of 0 stops the envelope.
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+/// The frequency sweep of channel 1, NR10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Sweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+
+    pub enabled: bool,
+    pub timer: u8,
+    /// The frequency the sweep calculations are made from.
+    pub shadow_frequency: u16,
+    /// Set once a calculation in negate mode has been made since the last trigger, clearing the
+    /// negate bit after that disables the channel.
+    pub negate_used: bool,
+}
+
+impl Sweep {
+    pub fn get_register(&self) -> u8 {
+        self.period << 4 | (self.negate as u8) << 3 | self.shift
+    }
+
+    // A period of 0 is treated as 8 by the sweep timer.
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // Calculates the next frequency, which disables the channel if it is above 2047.
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        }
+    }
+}
+
+/// Channels 1 and 2, square waves with 4 duty cycles. Only channel 1 has a sweep, on channel 2 it
+/// is never enabled.
+pub struct SquareChannel {
+    pub enabled: bool,
+    pub duty: u8,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub sweep: Sweep,
+    /// The 11 bit frequency value, the duty cycle advances every (2048 - frequency) * 4 clocks.
+    pub frequency: u16,
+    pub timer: u16,
+    pub duty_position: u8,
+}
+
+impl SquareChannel {
+    pub fn new() -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            duty: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            sweep: Sweep::default(),
+            frequency: 0,
+            timer: 0x2000,
+            duty_position: 0,
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    pub fn tick(&mut self, clocks: u8) {
+        let mut clocks = clocks as u16;
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            self.duty_position = (self.duty_position + 1) & 0x07;
+        }
+        self.timer -= clocks;
+    }
+
+    /// The current digital output from 0 to 15.
+    pub fn output(&self) -> u8 {
+        if self.enabled && get_bit(DUTY_PATTERNS[self.duty as usize], self.duty_position) {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn set_sweep(&mut self, n: u8) {
+        let negate = get_bit(n, 3);
+        if self.sweep.negate && !negate && self.sweep.negate_used {
+            self.enabled = false;
+        }
+        self.sweep.period = (n >> 4) & 0x07;
+        self.sweep.negate = negate;
+        self.sweep.shift = n & 0x07;
+    }
+
+    pub fn set_length_duty(&mut self, n: u8) {
+        self.duty = n >> 6;
+        self.length.load(n & 0x3f);
+    }
+
+    pub fn set_envelope(&mut self, n: u8) {
+        self.envelope.set_register(n);
+        if !self.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn set_frequency_low(&mut self, n: u8) {
+        self.frequency = make_word16(high_byte(self.frequency), n);
+    }
+
+    pub fn set_control(&mut self, n: u8, length_quirk: bool) {
+        self.frequency = make_word16(n & 0x07, low_byte(self.frequency));
+        if self.length.set_enabled(get_bit(n, 6), length_quirk) && !get_bit(n, 7) {
+            self.enabled = false;
+        }
+        if get_bit(n, 7) {
+            self.trigger(length_quirk);
+        }
+    }
+
+    fn trigger(&mut self, length_quirk: bool) {
+        self.enabled = self.dac_enabled();
+        self.length.trigger(length_quirk);
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        self.sweep.shadow_frequency = self.frequency;
+        self.sweep.reload_timer();
+        self.sweep.enabled = self.sweep.period != 0 || self.sweep.shift != 0;
+        self.sweep.negate_used = false;
+        if self.sweep.shift != 0 && self.sweep.calculate() > 2047 {
+            self.enabled = false;
+        }
+    }
+
+    /// Clocked at 128 Hz by the frame sequencer.
+    pub fn clock_sweep(&mut self) {
+        if self.sweep.timer > 0 {
+            self.sweep.timer -= 1;
+        }
+        if self.sweep.timer != 0 {
+            return;
+        }
+        self.sweep.reload_timer();
+
+        if self.sweep.enabled && self.sweep.period != 0 {
+            let frequency = self.sweep.calculate();
+            if frequency > 2047 {
+                self.enabled = false;
+            } else if self.sweep.shift != 0 {
+                self.sweep.shadow_frequency = frequency;
+                self.frequency = frequency;
+                // The new frequency is immediately checked for overflow again, but not used.
+                if self.sweep.calculate() > 2047 {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // The registers without their read masks, starting from NRx0.
+    fn get_registers(&self) -> [u8; 5] {
+        [self.sweep.get_register(),
+         self.duty << 6,
+         self.envelope.get_register(),
+         low_byte(self.frequency),
+         (self.length.enabled as u8) << 6 | high_byte(self.frequency)]
+    }
+}
+
+/// Channel 3, which plays back 32 4 bit samples from wave RAM.
+pub struct WaveChannel {
+    pub enabled: bool,
+    /// NR30 bit 7
+    pub dac_enabled: bool,
+    pub length: LengthCounter,
+    /// NR32 bits 5-6, 0 mutes the channel and 1-3 shift the samples right by 0-2 bits.
+    pub volume_code: u8,
+    /// The 11 bit frequency value, the position advances every (2048 - frequency) * 2 clocks.
+    pub frequency: u16,
+    pub timer: u16,
+    pub position: u8,
+    /// The last sample read from wave RAM, which is what the channel outputs.
+    pub sample_buffer: u8,
+    /// 0xff30-0xff3f, each byte holds two samples with the upper nibble played first.
+    pub wave_ram: [u8; 0x10],
+}
+
+impl WaveChannel {
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0x1000,
+            position: 0,
+            sample_buffer: 0,
+            wave_ram: [0x0; 0x10],
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    pub fn tick(&mut self, clocks: u8) {
+        let mut clocks = clocks as u16;
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1f;
+            let byte = self.wave_ram[self.position as usize / 2];
+            self.sample_buffer = if get_bit(self.position, 0) {
+                low_nibble(byte)
+            } else {
+                high_nibble(byte)
+            };
+        }
+        self.timer -= clocks;
+    }
+
+    /// The current digital output from 0 to 15.
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.volume_code != 0 {
+            self.sample_buffer >> (self.volume_code - 1)
+        } else {
+            0
+        }
+    }
+
+    /// While the channel is playing, wave RAM accesses go to the byte currently being played
+    /// regardless of the address.
+    pub fn get_wave_ram(&self, addr: u16) -> u8 {
+        if self.enabled {
+            self.wave_ram[self.position as usize / 2]
+        } else {
+            self.wave_ram[addr as usize & 0x0f]
+        }
+    }
+
+    pub fn set_wave_ram(&mut self, addr: u16, n: u8) {
+        if self.enabled {
+            self.wave_ram[self.position as usize / 2] = n;
+        } else {
+            self.wave_ram[addr as usize & 0x0f] = n;
+        }
+    }
+
+    pub fn set_dac(&mut self, n: u8) {
+        self.dac_enabled = get_bit(n, 7);
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    pub fn set_frequency_low(&mut self, n: u8) {
+        self.frequency = make_word16(high_byte(self.frequency), n);
+    }
+
+    pub fn set_control(&mut self, n: u8, length_quirk: bool) {
+        self.frequency = make_word16(n & 0x07, low_byte(self.frequency));
+        if self.length.set_enabled(get_bit(n, 6), length_quirk) && !get_bit(n, 7) {
+            self.enabled = false;
+        }
+        if get_bit(n, 7) {
+            self.enabled = self.dac_enabled;
+            self.length.trigger(length_quirk);
+            self.timer = self.period();
+            self.position = 0;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn get_registers(&self) -> [u8; 5] {
+        [(self.dac_enabled as u8) << 7,
+         0x0,
+         self.volume_code << 5,
+         low_byte(self.frequency),
+         (self.length.enabled as u8) << 6 | high_byte(self.frequency)]
+    }
+}
+
+/// Channel 4, pseudo random noise from a linear feedback shift register.
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    /// NR43 bits 4-7, values of 14 and 15 stop the LFSR.
+    pub clock_shift: u8,
+    /// NR43 bit 3, shortens the LFSR to 7 bits.
+    pub width_mode: bool,
+    /// NR43 bits 0-2
+    pub divisor_code: u8,
+    pub timer: u32,
+    pub lfsr: u16,
+}
+
+impl NoiseChannel {
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+            timer: 8,
+            lfsr: 0x7fff,
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    pub fn tick(&mut self, clocks: u8) {
+        let mut clocks = clocks as u32;
+        while clocks >= self.timer {
+            clocks -= self.timer;
+            self.timer = self.period();
+            if self.clock_shift < 14 {
+                let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x01;
+                self.lfsr = (self.lfsr >> 1) | bit << 14;
+                if self.width_mode {
+                    self.lfsr = set_bit(self.lfsr, 6, bit != 0);
+                }
+            }
+        }
+        self.timer -= clocks;
+    }
+
+    /// The current digital output from 0 to 15, the inverse of LFSR bit 0 selects the volume.
+    pub fn output(&self) -> u8 {
+        if self.enabled && !get_bit(self.lfsr, 0) {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn set_envelope(&mut self, n: u8) {
+        self.envelope.set_register(n);
+        if !self.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn set_polynomial(&mut self, n: u8) {
+        self.clock_shift = high_nibble(n);
+        self.width_mode = get_bit(n, 3);
+        self.divisor_code = n & 0x07;
+    }
+
+    pub fn set_control(&mut self, n: u8, length_quirk: bool) {
+        if self.length.set_enabled(get_bit(n, 6), length_quirk) && !get_bit(n, 7) {
+            self.enabled = false;
+        }
+        if get_bit(n, 7) {
+            self.enabled = self.dac_enabled();
+            self.length.trigger(length_quirk);
+            self.timer = self.period();
+            self.envelope.trigger();
+            self.lfsr = 0x7fff;
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn get_registers(&self) -> [u8; 5] {
+        [0x0,
+         0x0,
+         self.envelope.get_register(),
+         self.clock_shift << 4 | (self.width_mode as u8) << 3 | self.divisor_code,
+         (self.length.enabled as u8) << 6]
+    }
+}
+
+/// The audio processing unit, with registers from NR10 (0xff10) to NR52 (0xff26) and wave RAM at
+/// 0xff30-0xff3f.
+pub struct Apu {
+    /// NR52 bit 7, while off all registers except wave RAM are cleared and read only.
+    pub powered: bool,
+    /// NR50, the master volume for each output terminal.
+    pub master_volume: u8,
+    /// NR51, which channels are sent to each output terminal.
+    pub panning: u8,
+
+    pub channel1: SquareChannel,
+    pub channel2: SquareChannel,
+    pub channel3: WaveChannel,
+    pub channel4: NoiseChannel,
+
+    /// The next step of the 512 Hz frame sequencer, which clocks length on even steps, sweep on
+    /// steps 2 and 6 and envelopes on step 7.
+    pub frame_sequencer_step: u8,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            powered: false,
+            master_volume: 0x0,
+            panning: 0x0,
+            channel1: SquareChannel::new(),
+            channel2: SquareChannel::new(),
+            channel3: WaveChannel::new(),
+            channel4: NoiseChannel::new(),
+            frame_sequencer_step: 0,
+        }
+    }
+
+    /// Advances every channel by the given number of clocks at the normal speed clock rate,
+    /// regardless of cpu speed.
+    pub fn tick(&mut self, clocks: u8) {
+        if !self.powered {
+            return;
+        }
+        self.channel1.tick(clocks);
+        self.channel2.tick(clocks);
+        self.channel3.tick(clocks);
+        self.channel4.tick(clocks);
+    }
+
+    /// Clocks the frame sequencer, on each falling edge of DIV bit 4, or bit 5 in double speed mode.
+    pub fn clock_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+
+        let step = self.frame_sequencer_step;
+        if !get_bit(step, 0) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.envelope.clock();
+            self.channel2.envelope.clock();
+            self.channel4.envelope.clock();
+        }
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    /// The current digital output of each channel, from 0 to 15.
+    pub fn channel_outputs(&self) -> [u8; 4] {
+        [self.channel1.output(),
+         self.channel2.output(),
+         self.channel3.output(),
+         self.channel4.output()]
+    }
+
+    /// Whether each channel's DAC is powered, a channel with its DAC off contributes nothing to
+    /// the mix rather than a level of 0.
+    pub fn dac_enabled(&self) -> [bool; 4] {
+        [self.channel1.dac_enabled(),
+         self.channel2.dac_enabled(),
+         self.channel3.dac_enabled,
+         self.channel4.dac_enabled()]
+    }
+
+    /// The analog output of each channel's DAC, which maps digital 0 to 15 onto -1.0 to 1.0, or 0.0
+    /// while the DAC is off.
+    pub fn channel_levels(&self) -> [f32; 4] {
+        let mut levels = [0.0; 4];
+        let outputs = self.channel_outputs();
+        let dac_enabled = self.dac_enabled();
+        for (i, level) in levels.iter_mut().enumerate() {
+            if dac_enabled[i] {
+                *level = outputs[i] as f32 / 7.5 - 1.0;
+            }
+        }
+        levels
+    }
+
+    /// The left and right output levels from -1.0 to 1.0, after NR51 panning and NR50 master
+    /// volume.
+    pub fn mix(&self) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for (i, &level) in self.channel_levels().iter().enumerate() {
+            if get_bit(self.panning, i as u8 + 4) {
+                left += level;
+            }
+            if get_bit(self.panning, i as u8) {
+                right += level;
+            }
+        }
+
+        let left_volume = ((self.master_volume >> 4) & 0x07) + 1;
+        let right_volume = (self.master_volume & 0x07) + 1;
+        (left / 4.0 * left_volume as f32 / 8.0, right / 4.0 * right_volume as f32 / 8.0)
+    }
+
+    /// Reads a register from 0xff10 to 0xff3f.
+    pub fn get_register(&self, addr: u16) -> u8 {
+        if addr >= 0xff30 {
+            self.channel3.get_wave_ram(addr)
+        } else {
+            self.get_raw_register(addr) | READ_MASKS[addr as usize - 0xff10]
+        }
+    }
+
+    /// The value of a register from 0xff10 to 0xff2f including write only bits, as stored in save
+    /// states.
+    pub fn get_raw_register(&self, addr: u16) -> u8 {
+        match addr {
+            0xff10...0xff14 => self.channel1.get_registers()[addr as usize - 0xff10],
+            0xff15...0xff19 => self.channel2.get_registers()[addr as usize - 0xff15],
+            0xff1a...0xff1e => self.channel3.get_registers()[addr as usize - 0xff1a],
+            0xff1f...0xff23 => self.channel4.get_registers()[addr as usize - 0xff1f],
+            0xff24 => self.master_volume,
+            0xff25 => self.panning,
+            0xff26 => {
+                let mut n = (self.powered as u8) << 7;
+                n = set_bit(n, 0, self.channel1.enabled);
+                n = set_bit(n, 1, self.channel2.enabled);
+                n = set_bit(n, 2, self.channel3.enabled);
+                n = set_bit(n, 3, self.channel4.enabled);
+                n
+            }
+            _ => 0x0,
+        }
+    }
+
+    /// Writes a register from 0xff10 to 0xff3f.
+    pub fn set_register(&mut self, addr: u16, n: u8) {
+        if addr >= 0xff30 {
+            self.channel3.set_wave_ram(addr, n);
+            return;
+        }
+        if addr == 0xff26 {
+            self.set_power(get_bit(n, 7));
+            return;
+        }
+
+        if !self.powered {
+            // On DMG the length counters can still be loaded while the APU is off.
+            match addr {
+                0xff11 => self.channel1.length.load(n & 0x3f),
+                0xff16 => self.channel2.length.load(n & 0x3f),
+                0xff1b => self.channel3.length.load(n),
+                0xff20 => self.channel4.length.load(n & 0x3f),
+                _ => {}
+            }
+            return;
+        }
+
+        let length_quirk = get_bit(self.frame_sequencer_step, 0);
+        match addr {
+            0xff10 => self.channel1.set_sweep(n),
+            0xff11 => self.channel1.set_length_duty(n),
+            0xff12 => self.channel1.set_envelope(n),
+            0xff13 => self.channel1.set_frequency_low(n),
+            0xff14 => self.channel1.set_control(n, length_quirk),
+            0xff16 => self.channel2.set_length_duty(n),
+            0xff17 => self.channel2.set_envelope(n),
+            0xff18 => self.channel2.set_frequency_low(n),
+            0xff19 => self.channel2.set_control(n, length_quirk),
+            0xff1a => self.channel3.set_dac(n),
+            0xff1b => self.channel3.length.load(n),
+            0xff1c => self.channel3.volume_code = (n >> 5) & 0x03,
+            0xff1d => self.channel3.set_frequency_low(n),
+            0xff1e => self.channel3.set_control(n, length_quirk),
+            0xff20 => self.channel4.length.load(n & 0x3f),
+            0xff21 => self.channel4.set_envelope(n),
+            0xff22 => self.channel4.set_polynomial(n),
+            0xff23 => self.channel4.set_control(n, length_quirk),
+            0xff24 => self.master_volume = n,
+            0xff25 => self.panning = n,
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, powered: bool) {
+        if self.powered && !powered {
+            // Powering off clears every register, but wave RAM and the length counters survive.
+            let wave_ram = self.channel3.wave_ram;
+            let lengths = [self.channel1.length.counter,
+                           self.channel2.length.counter,
+                           self.channel3.length.counter,
+                           self.channel4.length.counter];
+            *self = Apu::new();
+            self.channel3.wave_ram = wave_ram;
+            self.channel1.length.counter = lengths[0];
+            self.channel2.length.counter = lengths[1];
+            self.channel3.length.counter = lengths[2];
+            self.channel4.length.counter = lengths[3];
+        } else if !self.powered && powered {
+            self.powered = true;
+            self.frame_sequencer_step = 0;
+        }
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u16(self.counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.counter = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.get_register());
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let n = reader.read_u8()?;
+        self.set_register(n);
+        self.volume = reader.read_u8()?;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.get_register());
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.timer);
+        writer.write_u16(self.shadow_frequency);
+        writer.write_bool(self.negate_used);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let n = reader.read_u8()?;
+        self.period = (n >> 4) & 0x07;
+        self.negate = get_bit(n, 3);
+        self.shift = n & 0x07;
+        self.enabled = reader.read_bool()?;
+        self.timer = reader.read_u8()?;
+        self.shadow_frequency = reader.read_u16()?;
+        self.negate_used = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        self.sweep.save_state(writer);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.duty_position);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.sweep.load_state(reader)?;
+        self.frequency = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.duty_position = reader.read_u8()?;
+        // Values the channel can't reach itself would hang or panic once it is ticked.
+        if self.timer == 0 || self.frequency > 0x7ff || self.duty > 3 {
+            return Err("invalid square channel state in save state".into());
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        self.length.save_state(writer);
+        writer.write_u8(self.volume_code);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.timer);
+        writer.write_u8(self.position);
+        writer.write_u8(self.sample_buffer);
+        writer.write_bytes(&self.wave_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.volume_code = reader.read_u8()?;
+        self.frequency = reader.read_u16()?;
+        self.timer = reader.read_u16()?;
+        self.position = reader.read_u8()?;
+        self.sample_buffer = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.wave_ram)?;
+        if self.timer == 0 || self.frequency > 0x7ff || self.position > 0x1f ||
+           self.volume_code > 3 {
+            return Err("invalid wave channel state in save state".into());
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+        writer.write_u8(self.clock_shift);
+        writer.write_bool(self.width_mode);
+        writer.write_u8(self.divisor_code);
+        writer.write_u32(self.timer);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.enabled = reader.read_bool()?;
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        self.clock_shift = reader.read_u8()?;
+        self.width_mode = reader.read_bool()?;
+        self.divisor_code = reader.read_u8()?;
+        self.timer = reader.read_u32()?;
+        self.lfsr = reader.read_u16()?;
+        if self.timer == 0 || self.clock_shift > 15 || self.divisor_code > 7 {
+            return Err("invalid noise channel state in save state".into());
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for Apu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.powered);
+        writer.write_u8(self.master_volume);
+        writer.write_u8(self.panning);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_u8(self.frame_sequencer_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.powered = reader.read_bool()?;
+        self.master_volume = reader.read_u8()?;
+        self.panning = reader.read_u8()?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.frame_sequencer_step = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.set_register(0xff26, 0x80);
+        apu
+    }
+
+    // Triggers channel 1 with its DAC on and the given NR10, NR11 and NR14 values.
+    fn trigger_channel1(apu: &mut Apu, sweep: u8, length_duty: u8, control: u8) {
+        apu.set_register(0xff10, sweep);
+        apu.set_register(0xff11, length_duty);
+        apu.set_register(0xff12, 0xf0);
+        apu.set_register(0xff14, 0x80 | control);
+    }
+
+    #[test]
+    fn register_read_masks() {
+        let mut apu = powered_apu();
+        for addr in 0xff10..0xff26 {
+            apu.set_register(addr, 0x00);
+        }
+        for addr in 0xff10..0xff30 {
+            let expected = if addr == 0xff26 {
+                0xf0
+            } else {
+                READ_MASKS[addr as usize - 0xff10]
+            };
+            assert_eq!(apu.get_register(addr), expected, "register {:x}", addr);
+        }
+
+        // Write only bits read back as set whatever was written.
+        apu.set_register(0xff11, 0x80);
+        assert_eq!(apu.get_register(0xff11), 0xbf);
+        apu.set_register(0xff13, 0x12);
+        assert_eq!(apu.get_register(0xff13), 0xff);
+        apu.set_register(0xff24, 0x35);
+        assert_eq!(apu.get_register(0xff24), 0x35);
+    }
+
+    #[test]
+    fn length_expires_and_disables_channel() {
+        let mut apu = powered_apu();
+        trigger_channel1(&mut apu, 0x00, 0x3f, 0x40);
+        assert!(apu.channel1.enabled);
+        assert_eq!(apu.channel1.length.counter, 1);
+
+        apu.clock_frame_sequencer();
+        assert!(!apu.channel1.enabled);
+        assert_eq!(apu.get_register(0xff26) & 0x01, 0);
+    }
+
+    #[test]
+    fn enabling_length_clocks_it_when_the_next_step_does_not() {
+        let mut apu = powered_apu();
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.frame_sequencer_step, 1);
+
+        trigger_channel1(&mut apu, 0x00, 0x3f, 0x00);
+        assert!(apu.channel1.enabled);
+        apu.set_register(0xff14, 0x40);
+        assert_eq!(apu.channel1.length.counter, 0);
+        assert!(!apu.channel1.enabled);
+
+        // Triggering with an expired counter reloads it, less the extra clock.
+        apu.set_register(0xff14, 0xc0);
+        assert!(apu.channel1.enabled);
+        assert_eq!(apu.channel1.length.counter, 63);
+    }
+
+    #[test]
+    fn length_can_be_loaded_while_powered_off() {
+        let mut apu = Apu::new();
+        apu.set_register(0xff11, 0x3e);
+        assert_eq!(apu.channel1.length.counter, 2);
+        apu.set_register(0xff12, 0xf0);
+        assert_eq!(apu.channel1.envelope.get_register(), 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_on_trigger_disables_channel() {
+        let mut apu = powered_apu();
+        apu.set_register(0xff13, 0xff);
+        trigger_channel1(&mut apu, 0x11, 0x00, 0x07);
+        assert!(!apu.channel1.enabled);
+    }
+
+    #[test]
+    fn sweep_overflow_on_clock_disables_channel() {
+        let mut apu = powered_apu();
+        apu.set_register(0xff13, 0x00);
+        trigger_channel1(&mut apu, 0x11, 0x00, 0x05);
+        assert!(apu.channel1.enabled);
+
+        // Sweep is clocked on step 2, 1280 goes up to 1920 and the next value 2880 overflows.
+        for _ in 0..3 {
+            apu.clock_frame_sequencer();
+        }
+        assert_eq!(apu.channel1.frequency, 1920);
+        assert!(!apu.channel1.enabled);
+    }
+
+    #[test]
+    fn power_off_clears_registers() {
+        let mut apu = powered_apu();
+        apu.set_register(0xff30, 0x12);
+        apu.set_register(0xff24, 0x77);
+        apu.set_register(0xff25, 0xff);
+        trigger_channel1(&mut apu, 0x11, 0xbe, 0x43);
+
+        apu.set_register(0xff26, 0x00);
+        for addr in 0xff10..0xff26 {
+            assert_eq!(apu.get_register(addr),
+                       READ_MASKS[addr as usize - 0xff10],
+                       "register {:x}",
+                       addr);
+        }
+        assert_eq!(apu.get_register(0xff26), 0x70);
+        assert_eq!(apu.get_register(0xff30), 0x12);
+        assert_eq!(apu.channel1.length.counter, 2);
+
+        // The registers can't be written until power is back on.
+        apu.set_register(0xff24, 0x77);
+        assert_eq!(apu.get_register(0xff24), 0x00);
+        apu.set_register(0xff26, 0x80);
+        apu.set_register(0xff24, 0x77);
+        assert_eq!(apu.get_register(0xff24), 0x77);
+    }
+
+    #[test]
+    fn load_state_rejects_zero_timer() {
+        let mut apu = powered_apu();
+        apu.channel2.timer = 0;
+        let mut writer = StateWriter::new();
+        apu.save_state(&mut writer);
+        assert!(Apu::new().load_state(&mut StateReader::new(&writer.data)).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+use std::cmp;
+use std::f64::consts::PI;
+
+use apu::*;
+
+/// The rate of the clock that the APU is ticked with.
+pub const APU_CLOCK_RATE: u32 = 4194304;
+
+// The length of the band-limited impulse in output samples and the number of fractional sample
+// positions it is tabulated at.
+const IMPULSE_TAPS: usize = 16;
+const IMPULSE_PHASES: usize = 64;
+// The impulse cutoff as a fraction of the output sample rate, slightly below Nyquist to leave room
+// for the window's transition band.
+const IMPULSE_CUTOFF: f64 = 0.45;
+
+// The per clock charge factors of the capacitor which blocks DC on the output.
+const DMG_CHARGE_FACTOR: f64 = 0.999958;
+const CGB_CHARGE_FACTOR: f64 = 0.998943;
+
+/// Converts the APU output to stereo samples at a host sample rate. The APU output only changes in
+/// steps, so rather than resampling it, each step is synthesized as a band-limited step at its
+/// exact position between output samples. At most a second of unread samples is kept, older ones
+/// are dropped.
+pub struct AudioOutput {
+    pub sample_rate: u32,
+
+    samples_per_clock: f64,
+    // The position of the current clock in output samples.
+    time: f64,
+    charge_factor: f32,
+    impulses: Vec<[f32; IMPULSE_TAPS]>,
+
+    left: StepSynth,
+    right: StepSynth,
+    // The output of each channel's DAC, before panning and master volume, when capturing.
+    channels: Option<[StepSynth; 4]>,
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: u32, cgb: bool) -> AudioOutput {
+        let clocks_per_sample = APU_CLOCK_RATE as f64 / sample_rate as f64;
+        let charge_factor = if cgb {
+            CGB_CHARGE_FACTOR
+        } else {
+            DMG_CHARGE_FACTOR
+        };
+
+        AudioOutput {
+            sample_rate,
+            samples_per_clock: sample_rate as f64 / APU_CLOCK_RATE as f64,
+            time: 0.0,
+            charge_factor: charge_factor.powf(clocks_per_sample) as f32,
+            impulses: impulse_table(),
+            left: StepSynth::new(),
+            right: StepSynth::new(),
+            channels: None,
+        }
+    }
+
+    /// Samples the APU output at the current time, then advances time by the given number of
+    /// clocks.
+    pub fn tick(&mut self, clocks: u8, apu: &Apu) {
+        let (left, right) = apu.mix();
+        self.left.set_level(self.time, left, &self.impulses);
+        self.right.set_level(self.time, right, &self.impulses);
+        if let Some(ref mut channels) = self.channels {
+            for (channel, &level) in channels.iter_mut().zip(apu.channel_levels().iter()) {
+                channel.set_level(self.time, level, &self.impulses);
+            }
+        }
+        self.time += clocks as f64 * self.samples_per_clock;
+        self.drop_old_samples();
+    }
+
+    // Drops the oldest samples of any stream which has more than a second of unread samples, so
+    // the buffers don't grow when nobody reads them.
+    fn drop_old_samples(&mut self) {
+        let max_samples = self.sample_rate as usize;
+        let time = self.time;
+        let charge_factor = self.charge_factor;
+        let synths = self.channels.iter_mut().flat_map(|channels| channels.iter_mut());
+        for synth in synths.chain(Some(&mut self.left)).chain(Some(&mut self.right)) {
+            let available = synth.available(time);
+            if available > max_samples {
+                synth.discard(available - max_samples, charge_factor);
+            }
+        }
+    }
+
+    /// Starts or stops capturing a separate mono stream for each channel, which must then be read
+    /// with read_channel_samples_f32 or read_channel_samples_i16.
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        if !enabled {
+            self.channels = None;
+        } else if self.channels.is_none() {
+            let start = self.time as u64;
+            self.channels = Some([StepSynth::starting_at(start),
+                                  StepSynth::starting_at(start),
+                                  StepSynth::starting_at(start),
+                                  StepSynth::starting_at(start)]);
+        }
+    }
+
+    /// The number of stereo samples which can be read.
+    pub fn samples_available(&self) -> usize {
+        self.left.available(self.time)
+    }
+
+    /// The number of samples of a channel from 0 to 3 which can be read, always 0 when not
+    /// capturing channels.
+    pub fn channel_samples_available(&self, channel: usize) -> usize {
+        match self.channels {
+            Some(ref channels) => channels[channel].available(self.time),
+            None => 0,
+        }
+    }
+
+    /// Reads mono samples of a channel from 0 to 3, returns the number of samples read.
+    pub fn read_channel_samples_f32(&mut self, channel: usize, buffer: &mut [f32]) -> usize {
+        let mut i = 0;
+        self.read_channel_samples(channel, buffer.len(), |sample| {
+            buffer[i] = sample;
+            i += 1;
+        })
+    }
+
+    /// Reads mono samples of a channel from 0 to 3, returns the number of samples read.
+    pub fn read_channel_samples_i16(&mut self, channel: usize, buffer: &mut [i16]) -> usize {
+        let mut i = 0;
+        self.read_channel_samples(channel, buffer.len(), |sample| {
+            buffer[i] = to_i16(sample);
+            i += 1;
+        })
+    }
+
+    /// Reads interleaved left and right samples into the buffer, returns the number of stereo
+    /// samples read.
+    pub fn read_samples_f32(&mut self, buffer: &mut [f32]) -> usize {
+        let mut i = 0;
+        self.read_samples(buffer.len() / 2, |left, right| {
+            buffer[i] = left;
+            buffer[i + 1] = right;
+            i += 2;
+        })
+    }
+
+    /// Reads interleaved left and right samples into the buffer, returns the number of stereo
+    /// samples read.
+    pub fn read_samples_i16(&mut self, buffer: &mut [i16]) -> usize {
+        let mut i = 0;
+        self.read_samples(buffer.len() / 2, |left, right| {
+            buffer[i] = to_i16(left);
+            buffer[i + 1] = to_i16(right);
+            i += 2;
+        })
+    }
+
+    fn read_samples<F: FnMut(f32, f32)>(&mut self, max_samples: usize, mut f: F) -> usize {
+        let count = cmp::min(max_samples, self.samples_available());
+        for i in 0..count {
+            let left = self.left.sample(i, self.charge_factor);
+            let right = self.right.sample(i, self.charge_factor);
+            f(left, right);
+        }
+        self.left.consume(count);
+        self.right.consume(count);
+        count
+    }
+
+    fn read_channel_samples<F: FnMut(f32)>(&mut self,
+                                           channel: usize,
+                                           max_samples: usize,
+                                           mut f: F)
+                                           -> usize {
+        let time = self.time;
+        let charge_factor = self.charge_factor;
+        let synth = match self.channels {
+            Some(ref mut channels) => &mut channels[channel],
+            None => return 0,
+        };
+
+        let count = cmp::min(max_samples, synth.available(time));
+        for i in 0..count {
+            f(synth.sample(i, charge_factor));
+        }
+        synth.consume(count);
+        count
+    }
+}
+
+// Accumulates band-limited steps for one output stream.
+struct StepSynth {
+    // The derivative of the output, each step adds a band-limited impulse which is integrated when
+    // samples are read.
+    deltas: Vec<f32>,
+    // The index of the sample at the start of deltas.
+    read_position: u64,
+    level: f32,
+    integrator: f32,
+    // The charge of the DC blocking capacitor.
+    capacitor: f32,
+}
+
+impl StepSynth {
+    fn new() -> StepSynth {
+        StepSynth::starting_at(0)
+    }
+
+    fn starting_at(read_position: u64) -> StepSynth {
+        StepSynth {
+            deltas: Vec::new(),
+            read_position,
+            level: 0.0,
+            integrator: 0.0,
+            capacitor: 0.0,
+        }
+    }
+
+    fn set_level(&mut self, time: f64, level: f32, impulses: &[[f32; IMPULSE_TAPS]]) {
+        let delta = level - self.level;
+        if delta == 0.0 {
+            return;
+        }
+        self.level = level;
+
+        let phase = (time.fract() * IMPULSE_PHASES as f64).round() as usize;
+        let position = (time as u64 - self.read_position) as usize;
+        if self.deltas.len() < position + IMPULSE_TAPS {
+            self.deltas.resize(position + IMPULSE_TAPS, 0.0);
+        }
+        for (d, &impulse) in self.deltas[position..].iter_mut().zip(impulses[phase].iter()) {
+            *d += delta * impulse;
+        }
+    }
+
+    // Samples before the current time are final, since later steps only affect later samples.
+    fn available(&self, time: f64) -> usize {
+        (time as u64 - self.read_position) as usize
+    }
+
+    // Integrates the sample at the given index and passes it through the high-pass filter, which
+    // must be done in order.
+    fn sample(&mut self, index: usize, charge_factor: f32) -> f32 {
+        self.integrator += self.deltas.get(index).cloned().unwrap_or(0.0);
+        let out = self.integrator - self.capacitor;
+        self.capacitor = self.integrator - out * charge_factor;
+        out
+    }
+
+    // Skips samples, they're still integrated so the level stays right.
+    fn discard(&mut self, count: usize, charge_factor: f32) {
+        for i in 0..count {
+            self.sample(i, charge_factor);
+        }
+        self.consume(count);
+    }
+
+    fn consume(&mut self, count: usize) {
+        let drained = cmp::min(count, self.deltas.len());
+        self.deltas.drain(..drained);
+        self.read_position += count as u64;
+    }
+}
+
+// Tabulates a Blackman windowed sinc impulse with unit sum at each fractional position, the last
+// phase is the first one delayed by a whole sample.
+fn impulse_table() -> Vec<[f32; IMPULSE_TAPS]> {
+    let half_width = IMPULSE_TAPS as f64 / 2.0;
+    (0..IMPULSE_PHASES + 1)
+        .map(|phase| {
+            let offset = phase as f64 / IMPULSE_PHASES as f64;
+            let mut impulse = [0.0; IMPULSE_TAPS];
+            for (k, v) in impulse.iter_mut().enumerate() {
+                let x = k as f64 - offset - (half_width - 1.0);
+                let sinc = if x == 0.0 {
+                    2.0 * IMPULSE_CUTOFF
+                } else {
+                    (2.0 * PI * IMPULSE_CUTOFF * x).sin() / (PI * x)
+                };
+                let window = if x.abs() < half_width {
+                    0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos()
+                } else {
+                    0.0
+                };
+                *v = sinc * window;
+            }
+            let sum: f64 = impulse.iter().sum();
+            let mut normalized = [0.0; IMPULSE_TAPS];
+            for (n, &v) in normalized.iter_mut().zip(impulse.iter()) {
+                *n = (v / sum) as f32;
+            }
+            normalized
+        })
+        .collect()
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_synth(synth: &mut StepSynth, count: usize, charge_factor: f32) -> Vec<f32> {
+        let samples = (0..count).map(|i| synth.sample(i, charge_factor)).collect();
+        synth.consume(count);
+        samples
+    }
+
+    #[test]
+    fn impulses_have_unit_sum() {
+        for impulse in impulse_table() {
+            let sum: f32 = impulse.iter().sum();
+            assert!((sum - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn step_settles_at_its_level() {
+        let impulses = impulse_table();
+        let mut synth = StepSynth::new();
+        synth.set_level(10.25, 0.5, &impulses);
+        let samples = read_synth(&mut synth, 40, 1.0);
+
+        assert!(samples[..10].iter().all(|&s| s == 0.0));
+        for &s in &samples[10 + IMPULSE_TAPS..] {
+            assert!((s - 0.5).abs() < 1e-5);
+        }
+        // The step is centered half the impulse length after its position.
+        let center = 10 + IMPULSE_TAPS / 2;
+        assert!(samples[center - 2] < 0.25 && samples[center] > 0.25);
+    }
+
+    #[test]
+    fn steps_accumulate() {
+        let impulses = impulse_table();
+        let mut synth = StepSynth::new();
+        synth.set_level(0.0, 1.0, &impulses);
+        synth.set_level(5.5, 0.25, &impulses);
+        // Setting the same level again adds nothing.
+        synth.set_level(6.0, 0.25, &impulses);
+        assert_eq!(synth.deltas.len(), 5 + IMPULSE_TAPS);
+
+        let samples = read_synth(&mut synth, 30, 1.0);
+        assert!((samples[29] - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn constant_level_settles_to_zero() {
+        let output = AudioOutput::new(44100, false);
+        let mut synth = StepSynth::new();
+        synth.set_level(0.0, 1.0, &output.impulses);
+        let samples = read_synth(&mut synth, 44100, output.charge_factor);
+
+        assert!(samples[IMPULSE_TAPS] > 0.9);
+        assert!(samples.windows(2).skip(IMPULSE_TAPS).all(|w| w[1] <= w[0]));
+        assert!(samples[44099].abs() < 0.01);
+    }
+
+    #[test]
+    fn unread_samples_are_capped() {
+        let apu = Apu::new();
+        let mut output = AudioOutput::new(8000, false);
+        output.set_channel_capture(true);
+        for _ in 0..APU_CLOCK_RATE * 2 / 255 {
+            output.tick(255, &apu);
+        }
+        assert_eq!(output.samples_available(), 8000);
+        assert_eq!(output.channel_samples_available(0), 8000);
+
+        let mut buffer = [0; 200];
+        assert_eq!(output.read_samples_i16(&mut buffer), 100);
+        assert_eq!(output.samples_available(), 7900);
+    }
+}
@@ -0,0 +1,472 @@
+use std::cmp;
+
+use util::*;
+use cpu::*;
+use ppu::*;
+use dma::*;
+use apu::*;
+use state::*;
+use emulator::*;
+use boot::*;
+use header::*;
+use mbc3::*;
+
+/// The Best Effort Save State format understood by SameBoy and other emulators. A file consists of
+/// emulator specific data, followed by a sequence of blocks with the same framing as rsgb state
+/// sections, followed by a footer with the offset of the first block and this magic.
+pub const BESS_MAGIC: &[u8; 4] = b"BESS";
+pub const BESS_MAJOR_VERSION: u16 = 1;
+pub const BESS_MINOR_VERSION: u16 = 1;
+
+/// Returns the offset of the first BESS block, if the data ends in a BESS footer.
+pub fn bess_offset(data: &[u8]) -> Option<usize> {
+    if data.len() < 8 || &data[data.len() - 4..] != BESS_MAGIC {
+        return None;
+    }
+    let mut offset = [0; 4];
+    offset.copy_from_slice(&data[data.len() - 8..data.len() - 4]);
+    let offset = u32::from_le_bytes(offset) as usize;
+    if offset > data.len() - 8 {
+        None
+    } else {
+        Some(offset)
+    }
+}
+
+/// Saves an rsgb state followed by BESS blocks, so that the result can be loaded both by
+/// Emulator::load_state and by other emulators.
+pub fn save_bess(emulator: &Emulator) -> Vec<u8> {
+    let mut writer = StateWriter { data: emulator.save_state() };
+
+    // The CORE block refers to memory by offset into the file, the memory is stored in a section
+    // which rsgb itself skips.
+    let wram = [&emulator.internal_ram_bank0[..], &emulator.internal_ram_bank1[..]].concat();
+    let vram = [&emulator.ppu.character_ram[..], &emulator.ppu.bg_map_data[..]].concat();
+    let buffers: [&[u8]; 5] = [&wram,
+                               &vram,
+                               emulator.cartridge.get_ram(),
+                               &emulator.ppu.sprite_attribute_data,
+                               &emulator.zero_page];
+    let mut buffer_offset = writer.data.len() + 8;
+    let mut buffer_locations = Vec::new();
+    for buffer in &buffers {
+        buffer_locations.push((buffer.len() as u32, buffer_offset as u32));
+        buffer_offset += buffer.len();
+    }
+    writer.write_section(b"BUFS", |w| for buffer in &buffers {
+        w.data.extend_from_slice(buffer);
+    });
+
+    // CORE must be the first block after NAME.
+    let first_block = writer.data.len();
+    writer.write_section(b"NAME", |w| {
+        w.data.extend_from_slice(concat!("rsgb ", env!("CARGO_PKG_VERSION")).as_bytes())
+    });
+
+    writer.write_section(b"CORE", |w| {
+        w.write_u16(BESS_MAJOR_VERSION);
+        w.write_u16(BESS_MINOR_VERSION);
+        w.data.extend_from_slice(bess_model(emulator.model));
+
+        w.write_u16(emulator.program_counter);
+        w.write_u16(get_af(emulator));
+        w.write_u16(get_bc(emulator));
+        w.write_u16(get_de(emulator));
+        w.write_u16(get_hl(emulator));
+        w.write_u16(emulator.stack_pointer);
+
+        w.write_bool(emulator.interrupt_master_enable);
+        w.write_u8(emulator.interrupt_enable);
+        w.write_u8(if emulator.stopped {
+                       2
+                   } else if emulator.halted {
+                       1
+                   } else {
+                       0
+                   });
+        w.write_u8(0);
+
+        for addr in 0xff00..0xff80 {
+            let n = match addr {
+                0xff10...0xff2f => emulator.apu.get_raw_register(addr),
+                0xff30...0xff3f => emulator.apu.channel3.wave_ram[addr as usize - 0xff30],
+                0xff50 => emulator.boot_rom.is_none() as u8,
+                _ => emulator.get_memory(addr).unwrap_or(0xff),
+            };
+            w.write_u8(n);
+        }
+
+        for &(size, offset) in &buffer_locations {
+            w.write_u32(size);
+            w.write_u32(offset);
+        }
+        // There are no CGB palettes in DMG mode.
+        for _ in 0..4 {
+            w.write_u32(0);
+        }
+    });
+
+    let rom = emulator.cartridge.get_rom();
+    if rom.len() >= 0x150 {
+        writer.write_section(b"INFO", |w| {
+            w.data.extend_from_slice(&rom[0x134..0x144]);
+            w.data.extend_from_slice(&rom[0x14e..0x150]);
+        });
+    }
+
+    let mapper_writes = emulator.cartridge.mapper_writes();
+    if !mapper_writes.is_empty() {
+        writer.write_section(b"MBC ", |w| for &(addr, n) in &mapper_writes {
+            w.write_u16(addr);
+            w.write_u8(n);
+        });
+    }
+
+    let footer = emulator.cartridge.save_battery_footer();
+    if !footer.is_empty() {
+        writer.write_section(b"RTC ", |w| w.data.extend_from_slice(&footer));
+    }
+
+    writer.write_section(b"END ", |_| {});
+    writer.write_u32(first_block as u32);
+    writer.data.extend_from_slice(BESS_MAGIC);
+    writer.data
+}
+
+/// Loads the BESS blocks of a state saved by any emulator, ignoring any emulator specific data
+/// before them. The emulator must already have the state's cartridge loaded. Every block is read
+/// and checked before anything is changed, so an invalid state leaves the emulator as it was.
+pub fn load_bess(emulator: &mut Emulator, data: &[u8]) -> Result<()> {
+    let first_block = bess_offset(data).ok_or("not a BESS save state")?;
+    let mut reader = StateReader::new(&data[first_block..data.len() - 8]);
+
+    let mut core = None;
+    let mut mapper_writes = Vec::new();
+    let mut footer = None;
+    loop {
+        let (tag, mut block) = reader.read_section()?;
+        match &tag {
+            b"CORE" => core = Some(read_core(&mut block, data)?),
+            b"MBC " => {
+                if block.data.len() % 3 != 0 {
+                    return Err(format!("invalid BESS MBC block size {}", block.data.len())
+                                   .into());
+                }
+                while block.has_remaining() {
+                    mapper_writes.push((block.read_u16()?, block.read_u8()?));
+                }
+            }
+            b"RTC " => {
+                if block.data.len() != RTC_FOOTER_SIZE && block.data.len() != RTC_FOOTER_SIZE_SHORT {
+                    return Err(format!("invalid BESS RTC block size {}", block.data.len())
+                                   .into());
+                }
+                footer = Some(block.data);
+            }
+            b"END " => break,
+            _ => {}
+        }
+    }
+
+    let core = core.ok_or("BESS save state has no CORE block")?;
+    let cgb_mode = core.model
+        .runs_in_cgb_mode(&CartridgeHeader::parse(emulator.cartridge.get_rom())?);
+    // The boot ROM itself is not saved, it must already be loaded if it was still mapped.
+    if core.io[0x50] == 0 && emulator.boot_rom.is_none() {
+        return Err("the state was saved while a boot rom was running".into());
+    }
+
+    apply_core(emulator, &core, cgb_mode);
+    for &(addr, n) in &mapper_writes {
+        match addr {
+            0x0000...0x7fff => emulator.cartridge.write_rom(addr, n),
+            0xa000...0xbfff => emulator.cartridge.write_ram(addr, n),
+            _ => {}
+        }
+    }
+    if let Some(footer) = footer {
+        emulator.cartridge.load_battery_footer(footer)?;
+    }
+    Ok(())
+}
+
+// The model is 4 characters, the family followed by the model and an optional revision, 'G' for
+// DMG, 'S' for SGB and 'C' for CGB.
+fn bess_model(model: Model) -> &'static [u8; 4] {
+    match model {
+        Model::Dmg0 | Model::Dmg => b"GD  ",
+        Model::Mgb => b"GM  ",
+        Model::Sgb => b"SN  ",
+        Model::Sgb2 => b"S2  ",
+        Model::Cgb => b"CC  ",
+        Model::Agb => b"CA  ",
+    }
+}
+
+fn parse_bess_model(model: &[u8]) -> Model {
+    match (model[0], model[1]) {
+        (b'G', b'M') => Model::Mgb,
+        (b'S', b'2') => Model::Sgb2,
+        (b'S', _) => Model::Sgb,
+        (b'C', b'A') => Model::Agb,
+        (b'C', _) => Model::Cgb,
+        _ => Model::Dmg,
+    }
+}
+
+// The contents of a CORE block, with the memory buffers it refers to.
+struct Core<'a> {
+    model: Model,
+    program_counter: u16,
+    af: u16,
+    bc: u16,
+    de: u16,
+    hl: u16,
+    stack_pointer: u16,
+    interrupt_master_enable: bool,
+    interrupt_enable: u8,
+    halted: bool,
+    stopped: bool,
+    io: &'a [u8],
+    wram: &'a [u8],
+    vram: &'a [u8],
+    cartridge_ram: &'a [u8],
+    oam: &'a [u8],
+    hram: &'a [u8],
+}
+
+fn read_core<'a>(block: &mut StateReader<'a>, data: &'a [u8]) -> Result<Core<'a>> {
+    let major_version = block.read_u16()?;
+    let minor_version = block.read_u16()?;
+    if major_version != BESS_MAJOR_VERSION {
+        return Err(format!("unsupported BESS version {}.{}", major_version, minor_version).into());
+    }
+
+    let model = parse_bess_model(block.read_slice(4)?);
+    let program_counter = block.read_u16()?;
+    let af = block.read_u16()?;
+    let bc = block.read_u16()?;
+    let de = block.read_u16()?;
+    let hl = block.read_u16()?;
+    let stack_pointer = block.read_u16()?;
+    let interrupt_master_enable = block.read_bool()?;
+    let interrupt_enable = block.read_u8()?;
+    let (halted, stopped) = match block.read_u8()? {
+        0 => (false, false),
+        1 => (true, false),
+        2 => (false, true),
+        s => return Err(format!("invalid BESS execution state {}", s).into()),
+    };
+    block.read_u8()?;
+    let io = block.read_slice(0x80)?;
+
+    Ok(Core {
+           model,
+           program_counter,
+           af,
+           bc,
+           de,
+           hl,
+           stack_pointer,
+           interrupt_master_enable,
+           interrupt_enable,
+           halted,
+           stopped,
+           io,
+           wram: read_buffer(block, data)?,
+           vram: read_buffer(block, data)?,
+           cartridge_ram: read_buffer(block, data)?,
+           oam: read_buffer(block, data)?,
+           hram: read_buffer(block, data)?,
+       })
+}
+
+fn apply_core(emulator: &mut Emulator, core: &Core, cgb_mode: bool) {
+    emulator.model = core.model;
+    emulator.cgb_mode = cgb_mode;
+
+    emulator.program_counter = core.program_counter;
+    set_af(emulator, core.af);
+    set_bc(emulator, core.bc);
+    set_de(emulator, core.de);
+    set_hl(emulator, core.hl);
+    emulator.stack_pointer = core.stack_pointer;
+
+    emulator.interrupt_master_enable = core.interrupt_master_enable;
+    emulator.enable_interrupts_pending = false;
+    emulator.interrupt_enable = core.interrupt_enable;
+    emulator.halted = core.halted;
+    emulator.stopped = core.stopped;
+    emulator.halt_bug = false;
+
+    load_io(emulator, core.io);
+
+    copy_buffer(&mut emulator.internal_ram_bank0, core.wram);
+    copy_buffer(&mut emulator.internal_ram_bank1, core.wram.get(0x1000..).unwrap_or(&[]));
+    copy_buffer(&mut emulator.ppu.character_ram, core.vram);
+    copy_buffer(&mut emulator.ppu.bg_map_data, core.vram.get(0x1800..).unwrap_or(&[]));
+    copy_buffer(emulator.cartridge.get_ram_mut(), core.cartridge_ram);
+    copy_buffer(&mut emulator.ppu.sprite_attribute_data, core.oam);
+    copy_buffer(&mut emulator.zero_page, core.hram);
+}
+
+// Restores the IO registers at 0xff00-0xff7f, setting internal state directly rather than
+// writing them as the cpu would, since writes to registers like DIV and LY have side effects.
+fn load_io(emulator: &mut Emulator, io: &[u8]) {
+    emulator.joypad.select = io[0x00] & 0x30;
+
+    emulator.timer.divider = make_word16(io[0x04], 0);
+    emulator.timer.counter = io[0x05];
+    emulator.timer.modulo = io[0x06];
+    emulator.timer.control = io[0x07] & 0x07;
+    emulator.timer.overflow = false;
+    emulator.timer.reloading = false;
+
+    emulator.interrupt_flag = io[0x0f] & 0x1f;
+
+    // Any non-zero value written to 0xff50 unmaps the boot ROM.
+    if io[0x50] != 0 {
+        emulator.boot_rom = None;
+    }
+
+    emulator.oam_dma = OamDma::new();
+    emulator.oam_dma.source = io[0x46];
+
+    // The sound registers are written as the cpu would, without triggering any channels, since
+    // BESS does not record the channels' internal state.
+    let apu = &mut emulator.apu;
+    *apu = Apu::new();
+    apu.channel3.wave_ram.copy_from_slice(&io[0x30..0x40]);
+    apu.set_register(0xff26, io[0x26]);
+    for addr in 0xff10..0xff26 {
+        let n = io[addr as usize - 0xff00];
+        let n = match addr {
+            0xff14 | 0xff19 | 0xff1e | 0xff23 => n & 0x7f,
+            _ => n,
+        };
+        apu.set_register(addr, n);
+    }
+
+    let ppu = &mut emulator.ppu;
+    ppu.lcd_control = io[0x40];
+    ppu.stat_interrupt_select = io[0x41] & 0x78;
+    ppu.scroll_y = io[0x42];
+    ppu.scroll_x = io[0x43];
+    ppu.line_compare = io[0x45];
+    ppu.bg_palette = io[0x47];
+    ppu.object_palette0 = io[0x48];
+    ppu.object_palette1 = io[0x49];
+    ppu.window_y = io[0x4a];
+    ppu.window_x = io[0x4b];
+    let mode = match io[0x41] & 0x03 {
+        0 => Mode::HBlank,
+        1 => Mode::VBlank,
+        2 => Mode::OamSearch,
+        _ => Mode::PixelTransfer,
+    };
+    if ppu.lcd_enabled() {
+        ppu.set_position(io[0x44], mode);
+    } else {
+        // The ppu starts from the beginning of the first line when the lcd is enabled.
+        ppu.set_position(0, Mode::OamSearch);
+    }
+
+    if emulator.cgb_mode {
+        emulator.double_speed = get_bit(io[0x4d], 7);
+        emulator.speed_switch_armed = get_bit(io[0x4d], 0);
+    } else {
+        emulator.double_speed = false;
+        emulator.speed_switch_armed = false;
+    }
+}
+
+// Reads a buffer's size and offset into the file from the CORE block.
+fn read_buffer<'a>(block: &mut StateReader, data: &'a [u8]) -> Result<&'a [u8]> {
+    let size = block.read_u32()? as usize;
+    let offset = block.read_u32()? as usize;
+    data.get(offset..offset.saturating_add(size))
+        .ok_or_else(|| format!("BESS buffer of {} bytes at {} is out of range", size, offset).into())
+}
+
+// Buffers of a different size than ours, such as the larger CGB memories, are truncated or leave
+// the rest of the destination untouched.
+fn copy_buffer(dst: &mut [u8], src: &[u8]) {
+    let len = cmp::min(dst.len(), src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn core_is_first_block_after_name() {
+        let emulator = Emulator::load_rom(&[0x0; 0x8000]).unwrap();
+        let data = save_bess(&emulator);
+        let first_block = bess_offset(&data).unwrap();
+
+        let mut reader = StateReader::new(&data[first_block..data.len() - 8]);
+        let mut tags = Vec::new();
+        while reader.has_remaining() {
+            tags.push(reader.read_section().unwrap().0);
+        }
+        assert_eq!(&tags[..3], &[*b"NAME", *b"CORE", *b"INFO"]);
+        assert_eq!(tags.last(), Some(b"END "));
+    }
+
+    #[test]
+    fn cgb_mode_needs_the_header_flag() {
+        let mut rom = vec![0x0; 0x8000];
+        let data = save_bess(&Emulator::load_rom_for_model(&rom, Model::Cgb).unwrap());
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        load_bess(&mut emulator, &data).unwrap();
+        assert_eq!(emulator.model, Model::Cgb);
+        assert!(!emulator.cgb_mode);
+
+        rom[0x143] = 0x80;
+        let data = save_bess(&Emulator::load_rom_for_model(&rom, Model::Cgb).unwrap());
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        load_bess(&mut emulator, &data).unwrap();
+        assert_eq!(emulator.model, Model::Cgb);
+        assert!(emulator.cgb_mode);
+    }
+
+    #[test]
+    fn boot_rom_mapping_follows_0xff50() {
+        let rom = vec![0x0; 0x8000];
+        let boot_rom = vec![0x0; 0x100];
+        let mut emulator = Emulator::load_rom_with_boot_rom(&rom, Model::Dmg, &boot_rom).unwrap();
+        let mapped = save_bess(&emulator);
+        emulator.set_memory(0xff50, 0x01).unwrap();
+        let unmapped = save_bess(&emulator);
+
+        let mut emulator = Emulator::load_rom_with_boot_rom(&rom, Model::Dmg, &boot_rom).unwrap();
+        load_bess(&mut emulator, &mapped).unwrap();
+        assert!(emulator.boot_rom.is_some());
+        load_bess(&mut emulator, &unmapped).unwrap();
+        assert!(emulator.boot_rom.is_none());
+
+        // A running boot ROM can't be restored without one.
+        assert!(load_bess(&mut emulator, &mapped).is_err());
+    }
+
+    #[test]
+    fn invalid_core_leaves_emulator_unchanged() {
+        let rom = vec![0x0; 0x8000];
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        emulator.program_counter = 0x1234;
+        let mut data = save_bess(&emulator);
+
+        // The execution state follows the version, model, registers, IME and IE.
+        let first_block = bess_offset(&data).unwrap();
+        let name_len = StateReader::new(&data[first_block + 4..]).read_u32().unwrap() as usize;
+        let core = first_block + 8 + name_len;
+        assert_eq!(&data[core..core + 4], b"CORE");
+        data[core + 8 + 22] = 0x07;
+
+        let mut emulator = Emulator::load_rom_for_model(&rom, Model::Cgb).unwrap();
+        let state = emulator.save_state();
+        assert!(load_bess(&mut emulator, &data).is_err());
+        assert_eq!(emulator.save_state(), state);
+    }
+}
@@ -0,0 +1,50 @@
+extern crate rsgb;
+
+use std::env;
+use std::io::Read;
+use std::fs::File;
+
+use rsgb::gbs::*;
+use rsgb::wav::*;
+
+const SAMPLE_RATE: u32 = 44100;
+
+fn main() {
+    let mut args = env::args();
+    args.next();
+    let gbs_filename = args.next().expect("no gbs argument given");
+    let song: u8 = args.next()
+        .expect("no song number given")
+        .parse()
+        .expect("could not parse song number");
+    let seconds: usize = args.next()
+        .expect("no length in seconds given")
+        .parse()
+        .expect("could not parse length");
+    let wav_filename = args.next().expect("no output wav name given");
+
+    let mut gbs_file = File::open(&gbs_filename).expect("could not open gbs file");
+
+    let mut gbs = Vec::new();
+    gbs_file
+        .read_to_end(&mut gbs)
+        .expect("could not read gbs");
+
+    // Songs are numbered from 1 on the command line, as in the gbs header.
+    let mut player = GbsPlayer::load(&gbs, SAMPLE_RATE).expect("could not load gbs");
+    player
+        .start_song(song.checked_sub(1).expect("songs are numbered from 1"))
+        .expect("could not start song");
+
+    let sample_count = (SAMPLE_RATE as usize)
+        .checked_mul(seconds)
+        .and_then(|n| n.checked_mul(2))
+        .expect("length too long");
+    let mut samples = vec![0; sample_count];
+    player
+        .render_i16(&mut samples)
+        .expect("emulation error");
+
+    let mut wav_file = File::create(&wav_filename).expect("could not create wav file");
+    write_wav(&mut wav_file, SAMPLE_RATE, 2, &samples).expect("could not write wav file");
+}
@@ -8,16 +8,23 @@ use image::{ImageBuffer, Luma};
 
 use rsgb::emulator::*;
 use rsgb::screen::*;
+use rsgb::wav::*;
+
+const SAMPLE_RATE: u32 = 44100;
 
 fn main() {
     let mut args = env::args();
     args.next();
     let rom_filename = args.next().expect("no rom argument given");
-    let step_count = args.next()
-        .expect("no step count given")
+    let frame_count = args.next()
+        .expect("no frame count given")
         .parse()
-        .expect("could not parse step count");
+        .expect("could not parse frame count");
     let image_filename = args.next().expect("no output image name given");
+    // Optionally the mixed audio is written to a WAV file, and each channel to a separate WAV file
+    // named with the given prefix followed by the channel number.
+    let wav_filename = args.next();
+    let stem_prefix = args.next();
 
     let mut rom_file = File::open(&rom_filename).expect("could not open rom file");
 
@@ -27,10 +34,28 @@ fn main() {
         .expect("could not read rom");
 
     let mut emulator = Emulator::load_rom(&rom).expect("could not load rom");
-    for i in 0..step_count {
+    if wav_filename.is_some() {
+        emulator.set_sample_rate(SAMPLE_RATE);
+        emulator.set_channel_capture(stem_prefix.is_some());
+    }
+    // Samples are read after every frame, the emulator only buffers a second of them.
+    let mut samples = Vec::new();
+    let mut stems = vec![Vec::new(); 4];
+    for i in 0..frame_count {
         emulator
-            .step()
-            .expect(&format!("emulation error at step {}", i));
+            .step_frame()
+            .expect(&format!("emulation error at frame {}", i));
+
+        if wav_filename.is_some() {
+            let start = samples.len();
+            samples.resize(start + emulator.samples_available() * 2, 0);
+            emulator.read_samples_i16(&mut samples[start..]);
+            for (channel, stem) in stems.iter_mut().enumerate() {
+                let start = stem.len();
+                stem.resize(start + emulator.channel_samples_available(channel), 0);
+                emulator.read_channel_samples_i16(channel, &mut stem[start..]);
+            }
+        }
     }
 
     let screen = emulator.get_screen();
@@ -46,4 +71,19 @@ fn main() {
     image
         .save(&image_filename)
         .expect("could not write image");
+
+    if let Some(wav_filename) = wav_filename {
+        let mut wav_file = File::create(&wav_filename).expect("could not create wav file");
+        write_wav(&mut wav_file, SAMPLE_RATE, 2, &samples).expect("could not write wav file");
+
+        if let Some(stem_prefix) = stem_prefix {
+            for (channel, stem) in stems.iter().enumerate() {
+                let stem_filename = format!("{}{}.wav", stem_prefix, channel + 1);
+                let mut stem_file = File::create(&stem_filename)
+                    .expect("could not create channel wav file");
+                write_wav(&mut stem_file, SAMPLE_RATE, 1, stem)
+                    .expect("could not write channel wav file");
+            }
+        }
+    }
 }
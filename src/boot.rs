@@ -0,0 +1,201 @@
+use util::*;
+use cpu::*;
+use header::*;
+use emulator::*;
+
+/// The Game Boy models, which differ in their boot ROM and the state it leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Model {
+    /// The first revision of the original Game Boy, with a different boot ROM.
+    Dmg0,
+    Dmg,
+    /// Game Boy Pocket and Light.
+    Mgb,
+    Sgb,
+    Sgb2,
+    Cgb,
+    /// Game Boy Advance running Game Boy software.
+    Agb,
+}
+
+impl Model {
+    /// Whether the model supports Color mode.
+    pub fn is_cgb(&self) -> bool {
+        matches!(*self, Model::Cgb | Model::Agb)
+    }
+
+    /// Whether a cartridge runs in Color mode on this model, which needs both a model with Color
+    /// support and the CGB flag in the cartridge header.
+    pub fn runs_in_cgb_mode(&self, header: &CartridgeHeader) -> bool {
+        self.is_cgb() && header.cgb_support() != CgbSupport::None
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(*self, Model::Sgb | Model::Sgb2)
+    }
+
+    /// The size of the model's boot ROM, CGB boot ROMs are also mapped at 0x0200-0x08ff.
+    pub fn boot_rom_size(&self) -> usize {
+        if self.is_cgb() { 0x900 } else { 0x100 }
+    }
+
+    pub fn to_u8(&self) -> u8 {
+        match *self {
+            Model::Dmg0 => 0,
+            Model::Dmg => 1,
+            Model::Mgb => 2,
+            Model::Sgb => 3,
+            Model::Sgb2 => 4,
+            Model::Cgb => 5,
+            Model::Agb => 6,
+        }
+    }
+
+    pub fn from_u8(n: u8) -> Result<Model> {
+        match n {
+            0 => Ok(Model::Dmg0),
+            1 => Ok(Model::Dmg),
+            2 => Ok(Model::Mgb),
+            3 => Ok(Model::Sgb),
+            4 => Ok(Model::Sgb2),
+            5 => Ok(Model::Cgb),
+            6 => Ok(Model::Agb),
+            _ => Err(format!("invalid model {}", n).into()),
+        }
+    }
+}
+
+// The (R) symbol drawn after the logo, which comes from the boot ROM rather than the cartridge.
+const REGISTERED_TILE: [u8; 8] = [0x3c, 0x42, 0xb9, 0xa5, 0xb9, 0xa5, 0x42, 0x3c];
+
+/// Puts the emulator in the state the boot ROM of its model leaves it in when it jumps to the
+/// cartridge at 0x0100. The cartridge must already be loaded and cgb_mode set.
+pub fn apply_post_boot_state(emulator: &mut Emulator) {
+    let model = emulator.model;
+    let header = CartridgeHeader::parse(emulator.cartridge.get_rom()).ok();
+
+    let (af, bc, de, hl) = post_boot_registers(model, emulator.cgb_mode, header.as_ref());
+    set_af(emulator, af);
+    set_bc(emulator, bc);
+    set_de(emulator, de);
+    set_hl(emulator, hl);
+    emulator.stack_pointer = 0xfffe;
+    emulator.program_counter = 0x0100;
+
+    emulator.interrupt_enable = 0x0;
+    emulator.interrupt_flag = set_bit(0, VBLANK_INTERRUPT, true);
+
+    // Only the upper byte of DIV is documented for the DMG models, the lower bits and the other
+    // models' values are approximate since they depend on the boot ROM's exact timing.
+    emulator.timer.divider = match model {
+        Model::Dmg0 => 0x1830,
+        Model::Dmg | Model::Mgb => 0xabcc,
+        Model::Sgb | Model::Sgb2 => 0xd85c,
+        Model::Cgb | Model::Agb => {
+            if emulator.cgb_mode { 0x1ea0 } else { 0x267c }
+        }
+    };
+
+    if model.is_cgb() {
+        emulator.oam_dma.source = 0x0;
+    }
+
+    emulator.ppu.set_lcd_control(0x91);
+    emulator.ppu.bg_palette = 0xfc;
+    emulator.ppu.object_palette0 = 0xff;
+    emulator.ppu.object_palette1 = 0xff;
+    if !model.is_cgb() {
+        if let Some(ref header) = header {
+            draw_logo(emulator, &header.logo);
+        }
+    }
+
+    let apu = &mut emulator.apu;
+    apu.set_register(0xff26, 0x80);
+    apu.set_register(0xff24, 0x77);
+    apu.set_register(0xff25, 0xf3);
+    apu.set_register(0xff11, 0x80);
+    apu.set_register(0xff12, 0xf3);
+    // The boot sound leaves channel 1 playing with its envelope faded out, except on the SGB which
+    // plays no sound.
+    if !model.is_sgb() {
+        apu.set_register(0xff13, 0xc1);
+        apu.set_register(0xff14, 0x87);
+        apu.channel1.envelope.volume = 0;
+    }
+}
+
+// Returns AF, BC, DE and HL.
+fn post_boot_registers(model: Model,
+                       cgb_mode: bool,
+                       header: Option<&CartridgeHeader>)
+                       -> (u16, u16, u16, u16) {
+    // The DMG boot ROM leaves the half carry and carry flags set unless the header checksum is 0.
+    let checksum_flags = match header {
+        Some(header) if header.header_checksum == 0 => 0x80,
+        _ => 0xb0,
+    };
+
+    match model {
+        Model::Dmg0 => (0x0100, 0xff13, 0x00c1, 0x8403),
+        Model::Dmg => (0x0100 | checksum_flags, 0x0013, 0x00d8, 0x014d),
+        Model::Mgb => (0xff00 | checksum_flags, 0x0013, 0x00d8, 0x014d),
+        Model::Sgb => (0x0100, 0x0014, 0x0000, 0xc060),
+        Model::Sgb2 => (0xff00, 0x0014, 0x0000, 0xc060),
+        Model::Cgb | Model::Agb if cgb_mode => {
+            if model == Model::Agb {
+                (0x1100, 0x0100, 0xff56, 0x000d)
+            } else {
+                (0x1180, 0x0000, 0xff56, 0x000d)
+            }
+        }
+        Model::Cgb | Model::Agb => {
+            // For Nintendo's own games the boot ROM picks a palette from the title checksum, which
+            // is left in B.
+            let b = match header {
+                Some(header) if header.licensee_code() == "01" => {
+                    header.title_checksum
+                }
+                _ => 0x0,
+            };
+            let hl = if b == 0x43 || b == 0x58 { 0x991a } else { 0x007c };
+            if model == Model::Agb {
+                // The AGB boot ROM finishes with INC B.
+                let b = b.wrapping_add(1);
+                let f = set_bit(set_bit(0, 7, b == 0), 5, b & 0x0f == 0);
+                (make_word16(0x11, f), make_word16(b, 0x00), 0x0008, hl)
+            } else {
+                (0x1180, make_word16(b, 0x00), 0x0008, hl)
+            }
+        }
+    }
+}
+
+// Draws the cartridge's logo the way the DMG boot ROMs do, as tiles 1-24 followed by the (R) as tile
+// 25, with each bit of the logo doubled in both directions.
+fn draw_logo(emulator: &mut Emulator, logo: &[u8]) {
+    let ppu = &mut emulator.ppu;
+    let mut rows = Vec::with_capacity(logo.len() * 4 + REGISTERED_TILE.len());
+    for &byte in logo {
+        for &nibble in &[high_nibble(byte), byte & 0x0f] {
+            let mut row = 0;
+            for bit in (0..4).rev() {
+                row = row << 2 | if get_bit(nibble, bit) { 0x3 } else { 0x0 };
+            }
+            rows.push(row);
+            rows.push(row);
+        }
+    }
+    rows.extend_from_slice(&REGISTERED_TILE);
+
+    // Only the low bitplane is written, starting at tile 1.
+    for (i, &row) in rows.iter().enumerate() {
+        ppu.character_ram[0x10 + i * 2] = row;
+    }
+
+    ppu.bg_map_data[0x110] = 0x19;
+    for i in 0..12 {
+        ppu.bg_map_data[0x104 + i] = i as u8 + 0x01;
+        ppu.bg_map_data[0x124 + i] = i as u8 + 0x0d;
+    }
+}
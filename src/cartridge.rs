@@ -0,0 +1,197 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use util::*;
+use state::*;
+use header::*;
+use mbc1::*;
+use mbc2::*;
+use mbc3::*;
+use mbc5::*;
+
+/// A cartridge owns its ROM, external RAM and any mapper registers, and handles all cpu accesses
+/// to 0x0000-0x7fff and 0xa000-0xbfff. Its save state covers everything except the ROM itself.
+pub trait Cartridge: SaveState {
+    /// The whole ROM image.
+    fn get_rom(&self) -> &[u8];
+
+    fn read_rom(&self, addr: u16) -> u8;
+    /// Writes to the ROM area go to the mapper registers, if any.
+    fn write_rom(&mut self, addr: u16, n: u8);
+
+    fn read_ram(&self, addr: u16) -> u8;
+    fn write_ram(&mut self, addr: u16, n: u8);
+
+    /// The whole external RAM, empty for carts without any.
+    fn get_ram(&self) -> &[u8];
+    fn get_ram_mut(&mut self) -> &mut [u8];
+
+    /// Battery backed state other than RAM, such as a real time clock, in the format appended to
+    /// RAM in .sav files.
+    fn save_battery_footer(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_battery_footer(&mut self, _footer: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Mapper register writes which restore the current banking state when replayed on a freshly
+    /// loaded cartridge, empty for cartridges without a mapper.
+    fn mapper_writes(&self) -> Vec<(u16, u8)> {
+        Vec::new()
+    }
+
+    /// Advances any clock on the cartridge by the given number of clocks at the normal speed clock
+    /// rate, regardless of cpu speed.
+    fn tick(&mut self, _clocks: u8) {}
+
+    /// Drives any real time clock on the cartridge from the given clock rather than from emulated
+    /// cycles, ignored by cartridges without a clock.
+    fn set_clock_source(&mut self, _clock_source: Box<dyn ClockSource>) {}
+
+    /// Whether the cartridge's rumble motor is currently on, always false without one.
+    fn rumble(&self) -> bool {
+        false
+    }
+}
+
+/// A source of time in whole seconds.
+pub trait ClockSource {
+    fn now(&self) -> u64;
+}
+
+/// Seconds since the unix epoch from the host's system clock.
+pub struct SystemClock;
+
+impl ClockSource for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+/// Selects the cartridge implementation from the cartridge type at header byte 0x147.
+pub fn load_cartridge(rom: &[u8]) -> Result<Box<dyn Cartridge>> {
+    let header = CartridgeHeader::parse(rom)?;
+
+    let rom_len = header
+        .rom_size_bytes()
+        .ok_or_else(|| format!("unsupported rom_size code {:x}", header.rom_size))?;
+    if rom.len() != rom_len {
+        return Err(format!("rom size mismatch, header rom_size code {:x} declares {} bytes but \
+                            the rom image is {} bytes",
+                           header.rom_size,
+                           rom_len,
+                           rom.len())
+                           .into());
+    }
+
+    let ram_len = header
+        .ram_size_bytes()
+        .ok_or_else(|| format!("unsupported ram_size code {:x}", header.ram_size))?;
+
+    match header.cartridge_type {
+        0x00 | 0x08 | 0x09 => Ok(Box::new(RomOnly::new(rom.to_vec(), vec![0x0; ram_len]))),
+        0x01...0x03 => Ok(Box::new(Mbc1::new(rom.to_vec(), vec![0x0; ram_len]))),
+        0x05 | 0x06 => Ok(Box::new(Mbc2::new(rom.to_vec()))),
+        t @ 0x0f...0x13 => {
+            let has_rtc = t == 0x0f || t == 0x10;
+            Ok(Box::new(Mbc3::new(rom.to_vec(), vec![0x0; ram_len], has_rtc)))
+        }
+        t @ 0x19...0x1e => {
+            let has_rumble = t >= 0x1c;
+            Ok(Box::new(Mbc5::new(rom.to_vec(), vec![0x0; ram_len], has_rumble)))
+        }
+        t => Err(format!("mbc / ram unsupported, cart type {:x}", t).into()),
+    }
+}
+
+/// Exports battery backed RAM in the .sav format shared with other emulators, the raw contents of
+/// RAM followed by an optional footer such as the RTC state.
+pub fn save_battery(cartridge: &dyn Cartridge) -> Vec<u8> {
+    let mut sav = cartridge.get_ram().to_vec();
+    sav.extend(cartridge.save_battery_footer());
+    sav
+}
+
+/// Imports battery backed RAM from a .sav file, the inverse of save_battery.
+pub fn load_battery(cartridge: &mut dyn Cartridge, sav: &[u8]) -> Result<()> {
+    let ram_len = cartridge.get_ram().len();
+    if sav.len() < ram_len {
+        return Err(format!("save size mismatch, cartridge has {} bytes of ram but the save is {} \
+                            bytes",
+                           ram_len,
+                           sav.len())
+                           .into());
+    }
+
+    cartridge
+        .get_ram_mut()
+        .copy_from_slice(&sav[0..ram_len]);
+    cartridge.load_battery_footer(&sav[ram_len..])
+}
+
+/// Reads from a ROM image at an offset which may lie past its end. Mappers can address more banks
+/// than a ROM has, so out of range bank numbers wrap around to the banks that exist.
+pub fn read_rom_offset(rom: &[u8], offset: usize) -> u8 {
+    rom[offset % rom.len()]
+}
+
+/// A cartridge with no mapper, at most 32 KiB of ROM and an optional unbanked RAM.
+pub struct RomOnly {
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> RomOnly {
+        RomOnly { rom, ram }
+    }
+}
+
+impl Cartridge for RomOnly {
+    fn get_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        read_rom_offset(&self.rom, addr as usize)
+    }
+
+    fn write_rom(&mut self, _addr: u16, _n: u8) {}
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if self.ram.is_empty() {
+            0xff
+        } else {
+            self.ram[(addr as usize - 0xa000) % self.ram.len()]
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, n: u8) {
+        if !self.ram.is_empty() {
+            let len = self.ram.len();
+            self.ram[(addr as usize - 0xa000) % len] = n;
+        }
+    }
+
+    fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+}
+
+impl SaveState for RomOnly {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.ram)
+    }
+}
@@ -2,6 +2,16 @@ use util::*;
 use instruction::*;
 use decoding::*;
 
+pub const INTERRUPT_FLAG_ADDRESS: u16 = 0xff0f;
+pub const INTERRUPT_ENABLE_ADDRESS: u16 = 0xffff;
+
+/// Bit numbers of the individual interrupts in the IF and IE registers, in priority order.
+pub const VBLANK_INTERRUPT: u8 = 0;
+pub const LCD_STAT_INTERRUPT: u8 = 1;
+pub const TIMER_INTERRUPT: u8 = 2;
+pub const SERIAL_INTERRUPT: u8 = 3;
+pub const JOYPAD_INTERRUPT: u8 = 4;
+
 #[derive(Debug, Clone, Copy)]
 pub struct Flags {
     pub zero: bool,
@@ -23,11 +33,25 @@ pub trait Cpu {
     fn get_stack_pointer(&self) -> u16;
     fn set_stack_pointer(&mut self, sp: u16);
 
+    /// The interrupt master enable flag (IME).
+    fn get_interrupts_enabled(&self) -> bool;
     fn set_interrupts_enabled(&mut self, enabled: bool);
 
+    /// Set by EI, IME becomes set only after the instruction following EI has started.
+    fn get_enable_interrupts_pending(&self) -> bool;
+    fn set_enable_interrupts_pending(&mut self, pending: bool);
+
     fn tick(&mut self, count: u8);
 
-    fn halt(&mut self);
+    /// Set by HALT, no instructions are executed until an interrupt is pending.
+    fn get_halted(&self) -> bool;
+    fn set_halted(&mut self, halted: bool);
+
+    /// Set when HALT is executed with IME unset and an interrupt already pending, the next opcode
+    /// fetch then fails to increment the program counter.
+    fn get_halt_bug(&self) -> bool;
+    fn set_halt_bug(&mut self, halt_bug: bool);
+
     fn stop(&mut self);
 
     fn get_memory(&self, addr: u16) -> Result<u8>;
@@ -35,9 +59,35 @@ pub trait Cpu {
 }
 
 pub fn step_cpu<C: Cpu>(cpu: &mut C) -> Result<()> {
+    let pending = get_pending_interrupts(cpu)?;
+
+    if cpu.get_halted() {
+        if pending == 0 {
+            cpu.tick(1);
+            return Ok(());
+        }
+        cpu.set_halted(false);
+    }
+
+    if cpu.get_interrupts_enabled() && pending != 0 {
+        return dispatch_interrupt(cpu, pending.trailing_zeros() as u8);
+    }
+
+    if cpu.get_enable_interrupts_pending() {
+        cpu.set_enable_interrupts_pending(false);
+        cpu.set_interrupts_enabled(true);
+    }
+
+    let mut halt_bug = cpu.get_halt_bug();
+    cpu.set_halt_bug(false);
+
     let instruction = decode_instruction(|| {
                                              let pc = cpu.get_program_counter();
-                                             cpu.set_program_counter(pc.checked_add(1).ok_or("program counter wrapped at 0xffff")?);
+                                             if halt_bug {
+                                                 halt_bug = false;
+                                             } else {
+                                                 cpu.set_program_counter(pc.checked_add(1).ok_or("program counter wrapped at 0xffff")?);
+                                             }
                                              cpu.get_memory(pc)
                                          })?;
 
@@ -573,7 +623,11 @@ pub fn step_cpu<C: Cpu>(cpu: &mut C) -> Result<()> {
         }
         HALT => {
             cpu.tick(1);
-            cpu.halt();
+            if !cpu.get_interrupts_enabled() && get_pending_interrupts(cpu)? != 0 {
+                cpu.set_halt_bug(true);
+            } else {
+                cpu.set_halted(true);
+            }
         }
         STOP => {
             cpu.tick(1);
@@ -581,11 +635,12 @@ pub fn step_cpu<C: Cpu>(cpu: &mut C) -> Result<()> {
         }
         DI => {
             cpu.tick(1);
+            cpu.set_enable_interrupts_pending(false);
             cpu.set_interrupts_enabled(false);
         }
         EI => {
             cpu.tick(1);
-            cpu.set_interrupts_enabled(true);
+            cpu.set_enable_interrupts_pending(true);
         }
 
         RLCA => {
@@ -965,6 +1020,27 @@ pub fn step_cpu<C: Cpu>(cpu: &mut C) -> Result<()> {
     Ok(())
 }
 
+// Returns the interrupts that are both requested in IF and enabled in IE.
+fn get_pending_interrupts<C: Cpu>(cpu: &C) -> Result<u8> {
+    let interrupt_flag = cpu.get_memory(INTERRUPT_FLAG_ADDRESS)?;
+    let interrupt_enable = cpu.get_memory(INTERRUPT_ENABLE_ADDRESS)?;
+    Ok(interrupt_flag & interrupt_enable & 0x1f)
+}
+
+// Acknowledges the given interrupt and calls its handler, which takes 5 machine cycles.
+fn dispatch_interrupt<C: Cpu>(cpu: &mut C, interrupt: u8) -> Result<()> {
+    cpu.tick(5);
+    cpu.set_interrupts_enabled(false);
+
+    let interrupt_flag = cpu.get_memory(INTERRUPT_FLAG_ADDRESS)?;
+    cpu.set_memory(INTERRUPT_FLAG_ADDRESS, set_bit(interrupt_flag, interrupt, false))?;
+
+    let pc = cpu.get_program_counter();
+    push_stack16(cpu, pc)?;
+    cpu.set_program_counter(0x40 + interrupt as u16 * 8);
+    Ok(())
+}
+
 fn bit_number(b: Bit) -> u8 {
     match b {
         Bit0 => 0,
@@ -1004,7 +1080,7 @@ fn set_memory16<C: Cpu>(cpu: &mut C, addr: u16, nn: u16) -> Result<()> {
     Ok(())
 }
 
-fn push_stack16<C: Cpu>(cpu: &mut C, nn: u16) -> Result<()> {
+pub fn push_stack16<C: Cpu>(cpu: &mut C, nn: u16) -> Result<()> {
     let sp = cpu.get_stack_pointer();
     let sp_dec = sp.checked_sub(2).ok_or("stack overflow")?;
     set_memory16(cpu, sp_dec, nn)?;
@@ -1019,7 +1095,7 @@ fn pop_stack16<C: Cpu>(cpu: &mut C) -> Result<u16> {
     Ok(nn)
 }
 
-fn get_af<C: Cpu>(cpu: &C) -> u16 {
+pub fn get_af<C: Cpu>(cpu: &C) -> u16 {
     let flags = cpu.get_flags();
     let mut f = 0;
     f = set_bit(f, 7, flags.zero);
@@ -1030,19 +1106,19 @@ fn get_af<C: Cpu>(cpu: &C) -> u16 {
     make_word16(cpu.get_register(ARegister), f)
 }
 
-fn get_bc<C: Cpu>(cpu: &C) -> u16 {
+pub fn get_bc<C: Cpu>(cpu: &C) -> u16 {
     make_word16(cpu.get_register(BRegister), cpu.get_register(CRegister))
 }
 
-fn get_de<C: Cpu>(cpu: &C) -> u16 {
+pub fn get_de<C: Cpu>(cpu: &C) -> u16 {
     make_word16(cpu.get_register(DRegister), cpu.get_register(ERegister))
 }
 
-fn get_hl<C: Cpu>(cpu: &C) -> u16 {
+pub fn get_hl<C: Cpu>(cpu: &C) -> u16 {
     make_word16(cpu.get_register(HRegister), cpu.get_register(LRegister))
 }
 
-fn set_af<C: Cpu>(cpu: &mut C, v: u16) {
+pub fn set_af<C: Cpu>(cpu: &mut C, v: u16) {
     cpu.set_register(ARegister, high_byte(v));
 
     let f = low_byte(v);
@@ -1054,17 +1130,17 @@ fn set_af<C: Cpu>(cpu: &mut C, v: u16) {
                   });
 }
 
-fn set_bc<C: Cpu>(cpu: &mut C, v: u16) {
+pub fn set_bc<C: Cpu>(cpu: &mut C, v: u16) {
     cpu.set_register(BRegister, high_byte(v));
     cpu.set_register(CRegister, low_byte(v));
 }
 
-fn set_de<C: Cpu>(cpu: &mut C, v: u16) {
+pub fn set_de<C: Cpu>(cpu: &mut C, v: u16) {
     cpu.set_register(DRegister, high_byte(v));
     cpu.set_register(ERegister, low_byte(v));
 }
 
-fn set_hl<C: Cpu>(cpu: &mut C, v: u16) {
+pub fn set_hl<C: Cpu>(cpu: &mut C, v: u16) {
     cpu.set_register(HRegister, high_byte(v));
     cpu.set_register(LRegister, low_byte(v));
 }
@@ -298,11 +298,11 @@ pub fn decode_instruction<F>(mut next_byte: F) -> Result<Instruction>
 
         0xd9 => Ok(RETI),
 
+        // STOP always skips the following byte, which is conventionally 0x00 but is ignored by the
+        // hardware whatever its value.
         0x10 => {
-            match next_byte()? {
-                0x00 => Ok(STOP),
-                b => Err(format!("Improper instruction byte {:x} following 0x10", b).into()),
-            }
+            next_byte()?;
+            Ok(STOP)
         }
 
         0xcb => {
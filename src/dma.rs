@@ -0,0 +1,170 @@
+use util::*;
+use state::*;
+
+pub const OAM_DMA_LENGTH: u8 = 0xa0;
+
+/// The OAM DMA unit, which copies 160 bytes from XX00-XX9F to OAM at one byte per machine cycle
+/// after a write of XX to DMA (0xff46).
+pub struct OamDma {
+    /// DMA (0xff46), reads back the last value written.
+    pub source: u8,
+    /// Set while a transfer is running, during which the cpu can only access HRAM and the IO
+    /// registers. The IO registers stay accessible so that DMA can be restarted, see
+    /// Emulator::get_memory.
+    pub active: bool,
+    /// The source page of the running transfer, which may differ from the last value written
+    /// while a restarted transfer is starting.
+    pub transfer_source: u8,
+    /// The index of the next byte the running transfer copies.
+    pub position: u8,
+    /// Machine cycles until a newly written transfer starts. A transfer already running keeps
+    /// going until then.
+    pub start_delay: u8,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            source: 0xff,
+            active: false,
+            transfer_source: 0x0,
+            position: 0,
+            start_delay: 0,
+        }
+    }
+
+    /// Handles a write to DMA (0xff46), the transfer starts after one machine cycle of setup.
+    pub fn start(&mut self, source: u8) {
+        self.source = source;
+        self.start_delay = 1;
+    }
+
+    /// Advances by a single machine cycle, returns the source address and OAM index of the byte
+    /// which should be copied during it.
+    pub fn tick(&mut self) -> Option<(u16, u8)> {
+        let copy = if self.active {
+            let index = self.position;
+            self.position += 1;
+            if self.position == OAM_DMA_LENGTH {
+                self.active = false;
+            }
+            Some((make_word16(self.transfer_source, index), index))
+        } else {
+            None
+        };
+
+        if self.start_delay > 0 {
+            self.start_delay -= 1;
+            if self.start_delay == 0 {
+                self.active = true;
+                self.transfer_source = self.source;
+                self.position = 0;
+            }
+        }
+
+        copy
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.source);
+        writer.write_bool(self.active);
+        writer.write_u8(self.transfer_source);
+        writer.write_u8(self.position);
+        writer.write_u8(self.start_delay);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.source = reader.read_u8()?;
+        self.active = reader.read_bool()?;
+        self.transfer_source = reader.read_u8()?;
+        self.position = reader.read_u8()?;
+        self.start_delay = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::*;
+    use emulator::*;
+
+    // An emulator with distinct values in the first two pages of work RAM.
+    fn dma_emulator() -> Emulator {
+        let mut emulator = Emulator::load_rom(&[0x0; 0x8000]).unwrap();
+        for i in 0..0x100 {
+            emulator.internal_ram_bank0[i] = i as u8;
+            emulator.internal_ram_bank0[0x100 + i] = !(i as u8);
+        }
+        emulator
+    }
+
+    #[test]
+    fn transfer_takes_160_cycles() {
+        let mut dma = OamDma::new();
+        dma.start(0xc0);
+        assert_eq!(dma.tick(), None);
+        for i in 0..OAM_DMA_LENGTH {
+            assert!(dma.active);
+            assert_eq!(dma.tick(), Some((0xc000 + i as u16, i)));
+        }
+        assert!(!dma.active);
+        assert_eq!(dma.tick(), None);
+    }
+
+    #[test]
+    fn oam_reads_0xff_during_transfer() {
+        let mut emulator = dma_emulator();
+        emulator.set_memory(0xff46, 0xc0).unwrap();
+        emulator.tick(2);
+        assert_eq!(emulator.get_memory(0xfe00).unwrap(), 0xff);
+        assert_eq!(emulator.get_memory(0xc005).unwrap(), 0xff);
+
+        emulator.tick(OAM_DMA_LENGTH - 1);
+        assert!(!emulator.oam_dma.active);
+        for i in 0..OAM_DMA_LENGTH as u16 {
+            assert_eq!(emulator.get_memory(0xfe00 + i).unwrap(), i as u8);
+        }
+        assert_eq!(emulator.get_memory(0xc005).unwrap(), 0x05);
+    }
+
+    #[test]
+    fn hram_stays_accessible_during_transfer() {
+        let mut emulator = dma_emulator();
+        emulator.set_memory(0xff46, 0xc0).unwrap();
+        emulator.tick(2);
+        assert!(emulator.oam_dma.active);
+
+        emulator.set_memory(0xff80, 0x42).unwrap();
+        assert_eq!(emulator.get_memory(0xff80).unwrap(), 0x42);
+        assert_eq!(emulator.get_memory(0xff46).unwrap(), 0xc0);
+
+        emulator.set_memory(0xc000, 0x42).unwrap();
+        emulator.tick(OAM_DMA_LENGTH);
+        assert_eq!(emulator.get_memory(0xc000).unwrap(), 0x00);
+    }
+
+    #[test]
+    fn restart_during_transfer() {
+        let mut emulator = dma_emulator();
+        emulator.set_memory(0xff46, 0xc0).unwrap();
+        emulator.tick(11);
+        assert_eq!(emulator.oam_dma.position, 10);
+
+        // The running transfer continues for one more cycle while the new one is set up.
+        emulator.set_memory(0xff46, 0xc1).unwrap();
+        emulator.tick(1);
+        assert_eq!(emulator.ppu.sprite_attribute_data[10], 10);
+        assert_eq!(emulator.oam_dma.position, 0);
+
+        emulator.tick(OAM_DMA_LENGTH - 1);
+        assert!(emulator.oam_dma.active);
+        emulator.tick(1);
+        assert!(!emulator.oam_dma.active);
+        for i in 0..OAM_DMA_LENGTH as usize {
+            assert_eq!(emulator.ppu.sprite_attribute_data[i], !(i as u8));
+        }
+    }
+}
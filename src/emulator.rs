@@ -2,9 +2,40 @@ use util::*;
 use cpu::*;
 use instruction::*;
 use screen::*;
+use ppu::*;
+use timer::*;
+use joypad::*;
+use dma::*;
+use apu::*;
+use audio::*;
+use cartridge::*;
+use header::*;
+use state::*;
+use bess::*;
+use boot::*;
 
 pub struct Emulator {
-    pub interrupts_enabled: u8,
+    pub interrupt_master_enable: bool,
+    pub enable_interrupts_pending: bool,
+    pub interrupt_enable: u8,
+    pub interrupt_flag: u8,
+
+    /// The model being emulated, which decides the post-boot state.
+    pub model: Model,
+    /// A boot ROM mapped over the start of the cartridge ROM, removed by a write to 0xff50.
+    pub boot_rom: Option<Vec<u8>>,
+
+    pub halted: bool,
+    pub halt_bug: bool,
+    pub stopped: bool,
+
+    /// Whether the cartridge runs in Color mode, which enables the CGB only registers.
+    pub cgb_mode: bool,
+    /// KEY1 bit 7, in double speed mode each cpu machine cycle takes half as many clocks.
+    pub double_speed: bool,
+    /// KEY1 bit 0, a STOP executed while this is set switches speed instead of stopping.
+    pub speed_switch_armed: bool,
+
     pub stack_pointer: u16,
     pub program_counter: u16,
 
@@ -18,22 +49,38 @@ pub struct Emulator {
 
     pub flags: Flags,
 
-    pub cartridge_rom_bank0: [u8; 0x4000],
-    pub cartridge_rom_bank1: [u8; 0x4000],
+    pub cartridge: Box<dyn Cartridge>,
 
     pub internal_ram_bank0: [u8; 0x1000],
     pub internal_ram_bank1: [u8; 0x1000],
     pub zero_page: [u8; 0x7f],
 
-    pub character_ram: [u8; 0x1800],
-    pub bg_map_data: [u8; 0x800],
-    pub sprite_attribute_data: [u8; 0x80],
+    pub ppu: Ppu,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub oam_dma: OamDma,
+    pub apu: Apu,
+    /// Only present once a sample rate has been set, so that samples are not buffered unless they
+    /// are read.
+    pub audio: Option<AudioOutput>,
 }
 
 impl Emulator {
+    /// Creates a DMG with no cartridge, starting at the cartridge entry point.
     pub fn new() -> Emulator {
         Emulator {
-            interrupts_enabled: 0x0f,
+            interrupt_master_enable: false,
+            enable_interrupts_pending: false,
+            interrupt_enable: 0x0f,
+            interrupt_flag: 0x0,
+            model: Model::Dmg,
+            boot_rom: None,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
             stack_pointer: 0xfffe,
             program_counter: 0x100,
             a_register: 0x0,
@@ -49,78 +96,426 @@ impl Emulator {
                 half_carry: false,
                 carry: false,
             },
-            cartridge_rom_bank0: [0x0; 0x4000],
-            cartridge_rom_bank1: [0x0; 0x4000],
+            cartridge: Box::new(RomOnly::new(vec![0x0; 0x8000], Vec::new())),
             internal_ram_bank0: [0x0; 0x1000],
             internal_ram_bank1: [0x0; 0x1000],
             zero_page: [0x0; 0x7f],
-            character_ram: [0x0; 0x1800],
-            bg_map_data: [0x0; 0x800],
-            sprite_attribute_data: [0x0; 0x80],
+            ppu: Ppu::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            oam_dma: OamDma::new(),
+            apu: Apu::new(),
+            audio: None,
         }
     }
 
+    /// Loads a cartridge in the state left by the DMG boot ROM. The CGB only hardware is not
+    /// emulated, so games supporting both are run in their DMG mode unless a CGB model is chosen
+    /// with load_rom_for_model.
     pub fn load_rom(rom: &[u8]) -> Result<Emulator> {
-        if rom.len() < 0x4000 {
-            return Err("rom size invalid".into());
+        Emulator::load_rom_for_model(rom, Model::Dmg)
+    }
+
+    /// Loads a cartridge in the state left by the given model's boot ROM.
+    pub fn load_rom_for_model(rom: &[u8], model: Model) -> Result<Emulator> {
+        let mut state = Emulator::power_on(rom, model)?;
+        apply_post_boot_state(&mut state);
+        Ok(state)
+    }
+
+    /// Loads a cartridge and starts running the given boot ROM from 0x0000, which must be the right
+    /// size for the model.
+    pub fn load_rom_with_boot_rom(rom: &[u8], model: Model, boot_rom: &[u8]) -> Result<Emulator> {
+        if boot_rom.len() != model.boot_rom_size() {
+            return Err(format!("boot rom size invalid, {:?} boot roms are {} bytes not {}",
+                               model,
+                               model.boot_rom_size(),
+                               boot_rom.len())
+                               .into());
         }
 
+        let mut state = Emulator::power_on(rom, model)?;
+        state.boot_rom = Some(boot_rom.to_vec());
+        Ok(state)
+    }
+
+    // The state at power-on, before any boot ROM has run.
+    fn power_on(rom: &[u8], model: Model) -> Result<Emulator> {
         let mut state = Emulator::new();
+        state.interrupt_enable = 0x0;
+        state.stack_pointer = 0x0;
+        state.program_counter = 0x0;
+        state.cartridge = load_cartridge(rom)?;
+        state.model = model;
+        state.cgb_mode = model.runs_in_cgb_mode(&CartridgeHeader::parse(rom)?);
+        Ok(state)
+    }
+
+    pub fn step(&mut self) -> Result<()> {
+        if self.stopped {
+            // The system clock does not run during STOP, only a selected joypad line going low can
+            // wake the cpu. Time still passes for the display and audio output so that frames and
+            // samples keep being produced.
+            if self.joypad.get_lines() == 0x0f {
+                let dots = if self.double_speed { 2 } else { 4 };
+                if let Some(ref mut audio) = self.audio {
+                    audio.tick(dots, &self.apu);
+                }
+                for _ in 0..dots {
+                    self.ppu.tick_stopped();
+                }
+                return Ok(());
+            }
+            self.stopped = false;
+        }
+
+        step_cpu(self)
+    }
+
+    /// Sets the given interrupt's bit in the IF register, it will be serviced once it is also
+    /// enabled in IE and IME is set.
+    pub fn request_interrupt(&mut self, interrupt: u8) {
+        self.interrupt_flag = set_bit(self.interrupt_flag, interrupt, true);
+    }
+
+    /// Exports the cartridge's battery backed RAM, and clock if it has one, in the .sav format.
+    pub fn save_battery(&self) -> Vec<u8> {
+        save_battery(&*self.cartridge)
+    }
+
+    /// Imports the cartridge's battery backed RAM from a .sav file.
+    pub fn load_battery(&mut self, sav: &[u8]) -> Result<()> {
+        load_battery(&mut *self.cartridge, sav)
+    }
+
+    /// Saves the complete emulator state, except for the cartridge ROM, which must be the same
+    /// when the state is loaded.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        writer.data.extend_from_slice(STATE_MAGIC);
+        writer.write_u32(STATE_VERSION);
+        let (global_checksum, cartridge_type) = self.rom_identity();
+        writer.write_u16(global_checksum);
+        writer.write_u8(cartridge_type);
 
-        let cart_type = rom[0x147];
-        let rom_size = rom[0x148];
+        writer.write_section(b"CPU ", |w| self.save_cpu_state(w));
+        writer.write_section(b"MEM ", |w| {
+            w.write_bytes(&self.internal_ram_bank0);
+            w.write_bytes(&self.internal_ram_bank1);
+            w.write_bytes(&self.zero_page);
+        });
+        writer.write_section(b"PPU ", |w| self.ppu.save_state(w));
+        writer.write_section(b"TIMR", |w| self.timer.save_state(w));
+        writer.write_section(b"JOYP", |w| self.joypad.save_state(w));
+        writer.write_section(b"DMA ", |w| self.oam_dma.save_state(w));
+        writer.write_section(b"APU ", |w| self.apu.save_state(w));
+        writer.write_section(b"CART", |w| self.cartridge.save_state(w));
 
-        // TODO: Actually implement MBC1
-        if cart_type != 0 && cart_type != 1 {
-            return Err(format!("mbc / ram unsupported, cart type {:x}", cart_type).into());
+        writer.data
+    }
+
+    /// Restores a state produced by save_state or save_bess_state for the same ROM. Sections
+    /// unknown to this version are skipped, and components without a section keep their current
+    /// state. If the state is invalid an error is returned and nothing is changed.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<()> {
+        // Any BESS blocks are redundant with the rsgb sections before them.
+        let state = match bess_offset(state) {
+            Some(offset) => &state[..offset],
+            None => state,
+        };
+
+        let mut reader = StateReader::new(state);
+        if reader.read_slice(4)? != STATE_MAGIC {
+            return Err("not an rsgb save state".into());
+        }
+        let version = reader.read_u32()?;
+        if version != STATE_VERSION {
+            return Err(format!("unsupported save state version {}", version).into());
+        }
+        let identity = (reader.read_u16()?, reader.read_u8()?);
+        if identity != self.rom_identity() {
+            return Err(format!("save state is for another rom, with global checksum {:04x} and \
+                                cart type {:x}",
+                               identity.0,
+                               identity.1)
+                               .into());
+        }
+
+        let mut sections = Vec::new();
+        while reader.has_remaining() {
+            sections.push(reader.read_section()?);
         }
 
-        match rom_size {
-            0 => {
-                if rom.len() != 0x8000 {
-                    return Err("rom size mismatch".into());
+        // Everything except the cartridge is loaded into a scratch emulator first, so that an
+        // invalid section is found before any state is changed.
+        let mut scratch = Emulator::new();
+        scratch.boot_rom = self.boot_rom.clone();
+        scratch.load_sections(&sections)?;
+
+        // The cartridge can't be copied, so it is loaded in place and put back from a snapshot if
+        // its section is invalid.
+        let mut snapshot = StateWriter::new();
+        self.cartridge.save_state(&mut snapshot);
+        for (tag, section) in &sections {
+            if tag == b"CART" {
+                if let Err(e) = self.cartridge.load_state(&mut StateReader::new(section.data)) {
+                    self.cartridge
+                        .load_state(&mut StateReader::new(&snapshot.data))?;
+                    return Err(e);
                 }
+            }
+        }
 
-                state
-                    .cartridge_rom_bank0
-                    .copy_from_slice(&rom[0..0x4000]);
-                state
-                    .cartridge_rom_bank1
-                    .copy_from_slice(&rom[0x4000..0x8000]);
+        self.load_sections(&sections)
+    }
+
+    // Loads each section into the matching component, except the cartridge's which load_state
+    // handles itself.
+    fn load_sections(&mut self, sections: &[([u8; 4], StateReader)]) -> Result<()> {
+        for (tag, section) in sections {
+            let mut section = StateReader::new(section.data);
+            match tag {
+                b"CPU " => self.load_cpu_state(&mut section)?,
+                b"MEM " => {
+                    section.read_bytes_into(&mut self.internal_ram_bank0)?;
+                    section.read_bytes_into(&mut self.internal_ram_bank1)?;
+                    section.read_bytes_into(&mut self.zero_page)?;
+                }
+                b"PPU " => self.ppu.load_state(&mut section)?,
+                b"TIMR" => self.timer.load_state(&mut section)?,
+                b"JOYP" => self.joypad.load_state(&mut section)?,
+                b"DMA " => self.oam_dma.load_state(&mut section)?,
+                b"APU " => self.apu.load_state(&mut section)?,
+                _ => {}
             }
-            s => return Err(format!("unsupported rom_size code {:x}", s).into()),
-        };
+        }
+        Ok(())
+    }
 
-        Ok(state)
+    // The global checksum and cartridge type from the header, which a save state must match.
+    fn rom_identity(&self) -> (u16, u8) {
+        let rom = self.cartridge.get_rom();
+        (make_word16(rom[0x14e], rom[0x14f]), rom[0x147])
     }
 
-    pub fn step(&mut self) -> Result<()> {
-        step_cpu(self)
+    /// Saves the complete emulator state followed by BESS blocks, which other emulators can load.
+    pub fn save_bess_state(&self) -> Vec<u8> {
+        save_bess(self)
+    }
+
+    /// Restores the BESS blocks of a state saved by another emulator. The same cartridge must
+    /// already be loaded.
+    pub fn load_bess_state(&mut self, state: &[u8]) -> Result<()> {
+        load_bess(self, state)
     }
 
+    fn save_cpu_state(&self, writer: &mut StateWriter) {
+        for &reg in &[ARegister, BRegister, CRegister, DRegister, ERegister, HRegister, LRegister] {
+            writer.write_u8(self.get_register(reg));
+        }
+        writer.write_bool(self.flags.zero);
+        writer.write_bool(self.flags.subtract);
+        writer.write_bool(self.flags.half_carry);
+        writer.write_bool(self.flags.carry);
+        writer.write_u16(self.stack_pointer);
+        writer.write_u16(self.program_counter);
+
+        writer.write_bool(self.interrupt_master_enable);
+        writer.write_bool(self.enable_interrupts_pending);
+        writer.write_u8(self.interrupt_enable);
+        writer.write_u8(self.interrupt_flag);
+
+        writer.write_bool(self.halted);
+        writer.write_bool(self.halt_bug);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.cgb_mode);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+
+        writer.write_u8(self.model.to_u8());
+        writer.write_bool(self.boot_rom.is_some());
+    }
+
+    fn load_cpu_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for &reg in &[ARegister, BRegister, CRegister, DRegister, ERegister, HRegister, LRegister] {
+            let v = reader.read_u8()?;
+            self.set_register(reg, v);
+        }
+        self.flags.zero = reader.read_bool()?;
+        self.flags.subtract = reader.read_bool()?;
+        self.flags.half_carry = reader.read_bool()?;
+        self.flags.carry = reader.read_bool()?;
+        self.stack_pointer = reader.read_u16()?;
+        self.program_counter = reader.read_u16()?;
+
+        self.interrupt_master_enable = reader.read_bool()?;
+        self.enable_interrupts_pending = reader.read_bool()?;
+        self.interrupt_enable = reader.read_u8()?;
+        self.interrupt_flag = reader.read_u8()?;
+
+        self.halted = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.cgb_mode = reader.read_bool()?;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+
+        self.model = Model::from_u8(reader.read_u8()?)?;
+        // The boot ROM itself is not saved, it must already be loaded if it was still mapped.
+        if !reader.read_bool()? {
+            self.boot_rom = None;
+        } else if self.boot_rom.is_none() {
+            return Err("the state was saved while a boot rom was running".into());
+        }
+        Ok(())
+    }
+
+    /// Sets the buttons which are currently held down, taking effect immediately.
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.interrupt_flag |= self.joypad.set_buttons(buttons);
+    }
+
+    /// Starts producing stereo audio samples at the given rate, replacing any unread samples.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.audio = Some(AudioOutput::new(sample_rate, self.cgb_mode));
+    }
+
+    /// The number of stereo samples which can be read, always 0 until a sample rate is set.
+    pub fn samples_available(&self) -> usize {
+        self.audio.as_ref().map_or(0, |a| a.samples_available())
+    }
+
+    /// Reads interleaved left and right samples, returns the number of stereo samples read.
+    pub fn read_samples_f32(&mut self, buffer: &mut [f32]) -> usize {
+        self.audio.as_mut().map_or(0, |a| a.read_samples_f32(buffer))
+    }
+
+    /// Reads interleaved left and right samples, returns the number of stereo samples read.
+    pub fn read_samples_i16(&mut self, buffer: &mut [i16]) -> usize {
+        self.audio.as_mut().map_or(0, |a| a.read_samples_i16(buffer))
+    }
+
+    /// Starts or stops capturing a separate mono stream for each channel, requires a sample rate to
+    /// have been set.
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        if let Some(ref mut audio) = self.audio {
+            audio.set_channel_capture(enabled);
+        }
+    }
+
+    /// The number of samples of a channel from 0 to 3 which can be read, always 0 when not capturing
+    /// channels.
+    pub fn channel_samples_available(&self, channel: usize) -> usize {
+        self.audio
+            .as_ref()
+            .map_or(0, |a| a.channel_samples_available(channel))
+    }
+
+    /// Reads mono samples of a channel from 0 to 3, returns the number of samples read.
+    pub fn read_channel_samples_f32(&mut self, channel: usize, buffer: &mut [f32]) -> usize {
+        self.audio
+            .as_mut()
+            .map_or(0, |a| a.read_channel_samples_f32(channel, buffer))
+    }
+
+    /// Reads mono samples of a channel from 0 to 3, returns the number of samples read.
+    pub fn read_channel_samples_i16(&mut self, channel: usize, buffer: &mut [i16]) -> usize {
+        self.audio
+            .as_mut()
+            .map_or(0, |a| a.read_channel_samples_i16(channel, buffer))
+    }
+
+    /// Steps the emulator until the ppu has completed the next frame.
+    pub fn step_frame(&mut self) -> Result<()> {
+        let frame_count = self.ppu.frame_count;
+        while self.ppu.frame_count == frame_count {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the last frame completed by the ppu.
     pub fn get_screen(&self) -> Screen {
-        let mut screen = Screen::new();
-        for y in 0..VERTICAL_SCREEN_PIXELS / 8 {
-            for x in 0..HORIZONTAL_SCREEN_PIXELS / 8 {
-                let tile = self.bg_map_data[y as usize * 32 + x as usize];
-                for ytile in 0..8 {
-                    let b1 = self.character_ram[tile as usize * 16 + ytile as usize * 2];
-                    let b2 = self.character_ram[tile as usize * 16 + ytile as usize * 2 + 1];
-                    for xtile in 0..8 {
-                        let column = 7 - xtile;
-                        screen.set_pixel(x * 8 + xtile,
-                                         y * 8 + ytile,
-                                         match (get_bit(b1, column), get_bit(b2, column)) {
-                                             (false, false) => Pixel::White,
-                                             (false, true) => Pixel::LightGray,
-                                             (true, false) => Pixel::DarkGray,
-                                             (true, true) => Pixel::Black,
-                                         });
-                    }
+        self.ppu.frame.clone()
+    }
+
+    // The DIV bit whose falling edge clocks the APU frame sequencer at 512 Hz.
+    fn frame_sequencer_signal(&self) -> bool {
+        let bit = if self.double_speed { 13 } else { 12 };
+        get_bit(self.timer.divider, bit)
+    }
+
+    // Resetting DIV can cause a falling edge for the APU frame sequencer as well as the timer.
+    fn reset_divider(&mut self) {
+        let old_signal = self.frame_sequencer_signal();
+        self.timer.reset_divider();
+        if old_signal {
+            self.apu.clock_frame_sequencer();
+        }
+    }
+
+    // The boot ROM covers 0x0000-0x00ff, and on CGB also 0x0200-0x08ff, leaving the cartridge
+    // header visible.
+    fn read_boot_rom(&self, addr: u16) -> Option<u8> {
+        let boot_rom = self.boot_rom.as_ref()?;
+        match addr {
+            0x100...0x1ff => None,
+            _ => boot_rom.get(addr as usize).cloned(),
+        }
+    }
+
+    // Reads memory as the cpu would with nothing blocking its access, OAM DMA reads its source
+    // through this.
+    fn read_memory(&self, addr: u16) -> Result<u8> {
+        if let Some(n) = self.read_boot_rom(addr) {
+            return Ok(n);
+        }
+
+        match addr {
+            0...0x7fff => Ok(self.cartridge.read_rom(addr)),
+            0x8000...0x97ff => Ok(self.ppu.character_ram[addr as usize - 0x8000]),
+            0x9800...0x9fff => Ok(self.ppu.bg_map_data[addr as usize - 0x9800]),
+            0xa000...0xbfff => Ok(self.cartridge.read_ram(addr)),
+            0xc000...0xcfff => Ok(self.internal_ram_bank0[addr as usize - 0xc000]),
+            0xd000...0xdfff => Ok(self.internal_ram_bank1[addr as usize - 0xd000]),
+            0xe000...0xfdff => self.read_memory(addr - 0x2000),
+            0xfe00...0xfe9f => Ok(self.ppu.sprite_attribute_data[addr as usize - 0xfe00]),
+            0xfea0...0xfeff => {
+                Err(format!("Illegal read from unusable memory region {}", addr).into())
+            }
+            0xff00 => Ok(self.joypad.get_p1()),
+            0xff04 => Ok(self.timer.get_divider()),
+            0xff05 => Ok(self.timer.counter),
+            0xff06 => Ok(self.timer.modulo),
+            0xff07 => Ok(self.timer.get_control()),
+            0xff0f => Ok(self.interrupt_flag | 0xe0),
+            0xff10...0xff3f => Ok(self.apu.get_register(addr)),
+            0xff40 => Ok(self.ppu.lcd_control),
+            0xff41 => Ok(self.ppu.get_lcd_status()),
+            0xff42 => Ok(self.ppu.scroll_y),
+            0xff43 => Ok(self.ppu.scroll_x),
+            0xff44 => Ok(self.ppu.line),
+            0xff45 => Ok(self.ppu.line_compare),
+            0xff46 => Ok(self.oam_dma.source),
+            0xff47 => Ok(self.ppu.bg_palette),
+            0xff48 => Ok(self.ppu.object_palette0),
+            0xff49 => Ok(self.ppu.object_palette1),
+            0xff4a => Ok(self.ppu.window_y),
+            0xff4b => Ok(self.ppu.window_x),
+            0xff4d => {
+                if self.cgb_mode {
+                    Ok(0x7e | (self.double_speed as u8) << 7 | self.speed_switch_armed as u8)
+                } else {
+                    Ok(0xff)
                 }
             }
+            0xff00...0xff7f => Ok(0x0), // TODO: Implement hardware registers
+            0xff80...0xfffe => Ok(self.zero_page[addr as usize - 0xff80]),
+            _ => {
+                assert_eq!(addr, 0xffff);
+                Ok(self.interrupt_enable)
+            }
         }
-        screen
     }
 }
 
@@ -173,57 +568,484 @@ impl Cpu for Emulator {
         self.stack_pointer = pc;
     }
 
-    fn set_interrupts_enabled(&mut self, _enabled: bool) {}
+    fn get_interrupts_enabled(&self) -> bool {
+        self.interrupt_master_enable
+    }
 
-    fn tick(&mut self, _count: u8) {}
+    fn set_interrupts_enabled(&mut self, enabled: bool) {
+        self.interrupt_master_enable = enabled;
+    }
 
-    fn halt(&mut self) {}
+    fn get_enable_interrupts_pending(&self) -> bool {
+        self.enable_interrupts_pending
+    }
 
-    fn stop(&mut self) {}
+    fn set_enable_interrupts_pending(&mut self, pending: bool) {
+        self.enable_interrupts_pending = pending;
+    }
 
-    fn get_memory(&self, addr: u16) -> Result<u8> {
-        match addr {
-            0...0x3fff => Ok(self.cartridge_rom_bank0[addr as usize]),
-            0x4000...0x7fff => Ok(self.cartridge_rom_bank1[addr as usize - 0x4000]),
-            0x8000...0x97ff => Ok(self.character_ram[addr as usize - 0x8000]),
-            0x9800...0x9fff => Ok(self.bg_map_data[addr as usize - 0x9800]),
-            0xa000...0xbfff => Err(format!("Illegal read from cartridge ram bank {}", addr).into()),
-            0xc000...0xcfff => Ok(self.internal_ram_bank0[addr as usize - 0xc000]),
-            0xd000...0xdfff => Ok(self.internal_ram_bank1[addr as usize - 0xd000]),
-            0xe000...0xfdff => self.get_memory(addr - 0x2000),
-            0xfe00...0xfe99 => Ok(self.sprite_attribute_data[addr as usize - 0xfe00]),
-            0xfea0...0xfeff => {
-                Err(format!("Illegal read from unusable memory region {}", addr).into())
+    fn tick(&mut self, count: u8) {
+        // The ppu runs at 4 dots per machine cycle, in double speed mode the cpu runs twice as fast
+        // relative to it.
+        let dots: u8 = if self.double_speed { 2 } else { 4 };
+        for _ in 0..count {
+            if let Some((source, index)) = self.oam_dma.tick() {
+                // Sources above 0xdfff read the echo of work RAM.
+                let source = if source >= 0xe000 { source - 0x2000 } else { source };
+                self.ppu.sprite_attribute_data[index as usize] =
+                    self.read_memory(source).unwrap_or(0xff);
             }
-            0xff00...0xff7f => Ok(0x0), // TODO: Implement hardware registers
-            0xff80...0xfffe => Ok(self.zero_page[addr as usize - 0xff80]),
-            _ => {
-                assert_eq!(addr, 0xffff);
-                Ok(self.interrupts_enabled)
+            let old_signal = self.frame_sequencer_signal();
+            self.interrupt_flag |= self.timer.tick();
+            if old_signal && !self.frame_sequencer_signal() {
+                self.apu.clock_frame_sequencer();
+            }
+            self.apu.tick(dots);
+            if let Some(ref mut audio) = self.audio {
+                audio.tick(dots, &self.apu);
+            }
+            self.cartridge.tick(dots);
+            for _ in 0..dots {
+                self.interrupt_flag |= self.ppu.tick();
             }
         }
     }
 
+    fn get_halted(&self) -> bool {
+        self.halted
+    }
+
+    fn set_halted(&mut self, halted: bool) {
+        self.halted = halted;
+    }
+
+    fn get_halt_bug(&self) -> bool {
+        self.halt_bug
+    }
+
+    fn set_halt_bug(&mut self, halt_bug: bool) {
+        self.halt_bug = halt_bug;
+    }
+
+    fn stop(&mut self) {
+        self.reset_divider();
+        if self.cgb_mode && self.speed_switch_armed {
+            self.double_speed = !self.double_speed;
+            self.speed_switch_armed = false;
+        } else {
+            self.stopped = true;
+        }
+    }
+
+    fn get_memory(&self, addr: u16) -> Result<u8> {
+        // During OAM DMA the cpu can only reach HRAM and the IO registers, everything else reads as
+        // 0xff. Only the buses the DMA uses are blocked, and the IO registers are on the cpu's
+        // internal bus along with HRAM. This is how a transfer can be restarted by writing 0xff46
+        // while one is running, as tested by mooneye-test-suite acceptance/oam_dma_restart and
+        // acceptance/oam_dma/reg_read, and as described in the OAM DMA chapter of Gekkio's "Game
+        // Boy: Complete Technical Reference".
+        if self.oam_dma.active && addr < 0xff00 {
+            return Ok(0xff);
+        }
+        self.read_memory(addr)
+    }
+
     fn set_memory(&mut self, addr: u16, n: u8) -> Result<()> {
+        if self.oam_dma.active && addr < 0xff00 {
+            return Ok(());
+        }
+
         match addr {
-            0...0x3fff => Err(format!("Illegal write to cartridge rom {}", addr).into()),
-            0x4000...0x7fff => Err(format!("Illegal write to cartridge rom {}", addr).into()),
-            0x8000...0x97ff => Ok(self.character_ram[addr as usize - 0x8000] = n),
-            0x9800...0x9fff => Ok(self.bg_map_data[addr as usize - 0x9800] = n),
-            0xa000...0xbfff => Err(format!("Illegal write to cartridge ram bank {}", addr).into()),
+            0...0x7fff => {
+                self.cartridge.write_rom(addr, n);
+                Ok(())
+            }
+            0x8000...0x97ff => Ok(self.ppu.character_ram[addr as usize - 0x8000] = n),
+            0x9800...0x9fff => Ok(self.ppu.bg_map_data[addr as usize - 0x9800] = n),
+            0xa000...0xbfff => {
+                self.cartridge.write_ram(addr, n);
+                Ok(())
+            }
             0xc000...0xcfff => Ok(self.internal_ram_bank0[addr as usize - 0xc000] = n),
             0xd000...0xdfff => Ok(self.internal_ram_bank1[addr as usize - 0xd000] = n),
             0xe000...0xfdff => self.set_memory(addr - 0x2000, n),
-            0xfe00...0xfe99 => Ok(self.sprite_attribute_data[addr as usize - 0xfe00] = n),
+            0xfe00...0xfe9f => Ok(self.ppu.sprite_attribute_data[addr as usize - 0xfe00] = n),
             0xfea0...0xfeff => {
                 Err(format!("Illegal write to unusable memory region {}", addr).into())
             }
+            0xff00 => {
+                self.interrupt_flag |= self.joypad.set_p1(n);
+                Ok(())
+            }
+            0xff04 => {
+                self.reset_divider();
+                Ok(())
+            }
+            0xff05 => {
+                self.timer.set_counter(n);
+                Ok(())
+            }
+            0xff06 => {
+                self.timer.set_modulo(n);
+                Ok(())
+            }
+            0xff07 => {
+                self.timer.set_control(n);
+                Ok(())
+            }
+            0xff0f => Ok(self.interrupt_flag = n & 0x1f),
+            0xff10...0xff3f => {
+                self.apu.set_register(addr, n);
+                Ok(())
+            }
+            0xff40 => {
+                self.ppu.set_lcd_control(n);
+                Ok(())
+            }
+            0xff41 => {
+                self.ppu.set_lcd_status(n);
+                Ok(())
+            }
+            0xff42 => Ok(self.ppu.scroll_y = n),
+            0xff43 => Ok(self.ppu.scroll_x = n),
+            0xff44 => Ok(()),
+            0xff45 => Ok(self.ppu.line_compare = n),
+            0xff46 => {
+                self.oam_dma.start(n);
+                Ok(())
+            }
+            0xff47 => Ok(self.ppu.bg_palette = n),
+            0xff48 => Ok(self.ppu.object_palette0 = n),
+            0xff49 => Ok(self.ppu.object_palette1 = n),
+            0xff4a => Ok(self.ppu.window_y = n),
+            0xff4b => Ok(self.ppu.window_x = n),
+            0xff4d => {
+                if self.cgb_mode {
+                    self.speed_switch_armed = get_bit(n, 0);
+                }
+                Ok(())
+            }
+            0xff50 => {
+                if n != 0 {
+                    self.boot_rom = None;
+                }
+                Ok(())
+            }
             0xff00...0xff7f => Ok(()), // TODO: Implement hardware registers
             0xff80...0xfffe => Ok(self.zero_page[addr as usize - 0xff80] = n),
             _ => {
                 assert_eq!(addr, 0xffff);
-                Ok(self.interrupts_enabled = n)
+                Ok(self.interrupt_enable = n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 32 KiB ROM with no mapper, running the given code from 0x0100.
+    fn test_rom(code: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0x0; 0x8000];
+        rom[0x100..0x100 + code.len()].copy_from_slice(code);
+        rom
+    }
+
+    // Keeps rewriting the tile data and horizontal scroll so that every frame differs.
+    fn animated_rom() -> Vec<u8> {
+        let mut rom = test_rom(&[0xc3, 0x50, 0x01]);
+        // LD HL,0x8000; loop: INC E; LD A,E; LD (HL+),A; LDH (0x43),A; LD A,H; CP 0x98;
+        // JR NZ,loop; LD H,0x80; JR loop
+        let code = [0x21, 0x00, 0x80, 0x1c, 0x7b, 0x22, 0xe0, 0x43, 0x7c, 0xfe, 0x98, 0x20, 0xf6,
+                    0x26, 0x80, 0x18, 0xf2];
+        rom[0x150..0x150 + code.len()].copy_from_slice(&code);
+        rom
+    }
+
+    fn screen_pixels(screen: &Screen) -> Vec<Pixel> {
+        let mut pixels = Vec::new();
+        for y in 0..VERTICAL_SCREEN_PIXELS {
+            for x in 0..HORIZONTAL_SCREEN_PIXELS {
+                pixels.push(screen.get_pixel(x, y));
+            }
+        }
+        pixels
+    }
+
+    // The number of machine cycles taken by a single step.
+    fn step_cycles(emulator: &mut Emulator) -> u16 {
+        let divider = emulator.timer.divider;
+        emulator.step().unwrap();
+        emulator.timer.divider.wrapping_sub(divider) / 4
+    }
+
+    #[test]
+    fn ei_takes_effect_after_the_next_instruction() {
+        // LD A,0x01; LDH (0xff),A; LDH (0x0f),A; EI; NOP; NOP
+        let rom = test_rom(&[0x3e, 0x01, 0xe0, 0xff, 0xe0, 0x0f, 0xfb, 0x00, 0x00]);
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        for _ in 0..4 {
+            emulator.step().unwrap();
+        }
+        assert!(!emulator.interrupt_master_enable);
+        assert_eq!(emulator.program_counter, 0x107);
+
+        // The instruction after EI still runs before the pending interrupt is serviced.
+        emulator.step().unwrap();
+        assert!(emulator.interrupt_master_enable);
+        assert_eq!(emulator.program_counter, 0x108);
+
+        emulator.step().unwrap();
+        assert_eq!(emulator.program_counter, 0x40);
+    }
+
+    #[test]
+    fn interrupts_dispatch_by_priority() {
+        let rom = test_rom(&[0x00]);
+        for interrupt in 0..5 {
+            let mut emulator = Emulator::load_rom(&rom).unwrap();
+            emulator.interrupt_master_enable = true;
+            emulator.interrupt_enable = 0x1f;
+            emulator.interrupt_flag = 0x1f << interrupt & 0x1f;
+
+            assert_eq!(step_cycles(&mut emulator), 5);
+            assert_eq!(emulator.program_counter, 0x40 + interrupt as u16 * 8);
+            assert!(!emulator.interrupt_master_enable);
+            assert!(!get_bit(emulator.interrupt_flag, interrupt));
+            for other in interrupt + 1..5 {
+                assert!(get_bit(emulator.interrupt_flag, other));
             }
+            assert_eq!(emulator.stack_pointer, 0xfffc);
+            assert_eq!(emulator.read_memory(0xfffc).unwrap(), 0x00);
+            assert_eq!(emulator.read_memory(0xfffd).unwrap(), 0x01);
+        }
+    }
+
+    #[test]
+    fn halt_wakes_without_dispatch_when_ime_is_unset() {
+        // HALT; NOP
+        let rom = test_rom(&[0x76, 0x00]);
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        emulator.interrupt_enable = 0x04;
+        emulator.interrupt_flag = 0x00;
+        emulator.step().unwrap();
+        assert!(emulator.halted);
+
+        for _ in 0..10 {
+            assert_eq!(step_cycles(&mut emulator), 1);
+        }
+        assert!(emulator.halted);
+        assert_eq!(emulator.program_counter, 0x101);
+
+        emulator.request_interrupt(TIMER_INTERRUPT);
+        emulator.step().unwrap();
+        assert!(!emulator.halted);
+        assert_eq!(emulator.program_counter, 0x102);
+        assert!(get_bit(emulator.interrupt_flag, TIMER_INTERRUPT));
+    }
+
+    #[test]
+    fn halt_bug_reads_the_next_opcode_twice() {
+        // HALT; INC A; NOP
+        let rom = test_rom(&[0x76, 0x3c, 0x00]);
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        emulator.interrupt_enable = 0x04;
+        emulator.interrupt_flag = 0x04;
+        emulator.a_register = 0x00;
+        emulator.step().unwrap();
+        assert!(!emulator.halted);
+        assert!(emulator.halt_bug);
+
+        emulator.step().unwrap();
+        assert_eq!(emulator.program_counter, 0x101);
+        emulator.step().unwrap();
+        assert_eq!(emulator.program_counter, 0x102);
+        assert_eq!(emulator.a_register, 0x02);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let rom = animated_rom();
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        for _ in 0..3 {
+            emulator.step_frame().unwrap();
+        }
+        let state = emulator.save_state();
+
+        let mut restored = Emulator::load_rom(&rom).unwrap();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.save_state(), state);
+
+        for _ in 0..5 {
+            emulator.step_frame().unwrap();
+            restored.step_frame().unwrap();
+            assert!(screen_pixels(&restored.get_screen()) == screen_pixels(&emulator.get_screen()));
+        }
+        assert_eq!(restored.save_state(), emulator.save_state());
+    }
+
+    #[test]
+    fn load_state_skips_unknown_sections() {
+        let rom = animated_rom();
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        emulator.step_frame().unwrap();
+        let state = emulator.save_state();
+
+        let mut writer = StateWriter { data: state.clone() };
+        writer.write_section(b"XTRA", |w| w.write_u32(0x12345678));
+
+        let mut restored = Emulator::load_rom(&rom).unwrap();
+        restored.load_state(&writer.data).unwrap();
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn load_state_rejects_invalid_states() {
+        let rom = animated_rom();
+        let state = Emulator::load_rom(&rom).unwrap().save_state();
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+
+        assert!(emulator.load_state(&state[..state.len() - 10]).is_err());
+        assert!(emulator.load_state(&state[..6]).is_err());
+        assert!(emulator.load_state(b"NOPE").is_err());
+
+        let mut newer = state.clone();
+        newer[4..8].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(emulator.load_state(&newer).is_err());
+
+        let mut older = state.clone();
+        older[4..8].copy_from_slice(&(STATE_VERSION - 1).to_le_bytes());
+        assert!(emulator.load_state(&older).is_err());
+    }
+
+    #[test]
+    fn load_state_rejects_states_for_other_roms() {
+        let rom = animated_rom();
+        let state = Emulator::load_rom(&rom).unwrap().save_state();
+
+        let mut other_checksum = rom.clone();
+        other_checksum[0x14e] = 0x12;
+        let mut emulator = Emulator::load_rom(&other_checksum).unwrap();
+        assert!(emulator.load_state(&state).is_err());
+
+        let mut other_type = rom.clone();
+        other_type[0x147] = 0x01;
+        let mut emulator = Emulator::load_rom(&other_type).unwrap();
+        assert!(emulator.load_state(&state).is_err());
+    }
+
+    #[test]
+    fn invalid_state_leaves_emulator_unchanged() {
+        let rom = animated_rom();
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        emulator.step_frame().unwrap();
+        let state = emulator.save_state();
+        emulator.step_frame().unwrap();
+        let current = emulator.save_state();
+
+        // Valid sections are followed by an invalid one, for both the scratch loaded components and
+        // the cartridge.
+        for &tag in &[b"PPU ", b"CART"] {
+            let mut writer = StateWriter { data: state.clone() };
+            writer.write_section(tag, |w| w.write_u8(0x0));
+            assert!(emulator.load_state(&writer.data).is_err());
+            assert_eq!(emulator.save_state(), current);
         }
     }
+
+    #[test]
+    fn stop_waits_for_a_joypad_press() {
+        // LD A,0x10; LDH (0x0f),A; XOR A; LDH (0x00),A; STOP
+        let rom = test_rom(&[0x3e, 0x10, 0xe0, 0x0f, 0xaf, 0xe0, 0x00, 0x10, 0x00]);
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        for _ in 0..5 {
+            emulator.step().unwrap();
+        }
+        assert!(emulator.stopped);
+
+        // The joypad bit set in IF before STOP does not count as a press.
+        let program_counter = emulator.program_counter;
+        for _ in 0..10 {
+            emulator.step().unwrap();
+        }
+        assert!(emulator.stopped);
+        assert_eq!(emulator.program_counter, program_counter);
+
+        emulator.set_buttons(Buttons {
+                                 a: true,
+                                 ..Buttons::default()
+                             });
+        emulator.step().unwrap();
+        assert!(!emulator.stopped);
+        assert_eq!(emulator.program_counter, program_counter + 1);
+    }
+
+    #[test]
+    fn frames_complete_while_stopped() {
+        // LD A,0x00; LDH (0x00),A; STOP
+        let rom = test_rom(&[0x3e, 0x00, 0xe0, 0x00, 0x10, 0x00]);
+        let mut emulator = Emulator::load_rom(&rom).unwrap();
+        for _ in 0..3 {
+            emulator.step().unwrap();
+        }
+        assert!(emulator.stopped);
+
+        let frame_count = emulator.ppu.frame_count;
+        emulator.step_frame().unwrap();
+        emulator.step_frame().unwrap();
+        assert_eq!(emulator.ppu.frame_count, frame_count + 2);
+        assert!(emulator.stopped);
+
+        emulator.set_sample_rate(44100);
+        while emulator.samples_available() < 1000 {
+            emulator.step().unwrap();
+        }
+        assert!(emulator.stopped);
+
+        emulator.set_buttons(Buttons {
+                                 a: true,
+                                 ..Buttons::default()
+                             });
+        emulator.step().unwrap();
+        assert!(!emulator.stopped);
+    }
+
+    #[test]
+    fn load_rom_runs_dual_mode_games_as_dmg() {
+        let mut rom = test_rom(&[]);
+        rom[0x143] = 0x80;
+        let emulator = Emulator::load_rom(&rom).unwrap();
+        assert_eq!(emulator.model, Model::Dmg);
+        assert!(!emulator.cgb_mode);
+        assert_eq!(emulator.a_register, 0x01);
+
+        let emulator = Emulator::load_rom_for_model(&rom, Model::Cgb).unwrap();
+        assert!(emulator.cgb_mode);
+        assert_eq!(emulator.a_register, 0x11);
+    }
+
+    #[test]
+    fn new_starts_at_the_entry_point() {
+        let emulator = Emulator::new();
+        assert_eq!(emulator.program_counter, 0x100);
+        assert_eq!(emulator.stack_pointer, 0xfffe);
+        assert!(emulator.boot_rom.is_none());
+    }
+
+    #[test]
+    fn boot_rom_is_unmapped_by_non_zero_writes() {
+        let rom = test_rom(&[]);
+        let mut emulator = Emulator::load_rom_with_boot_rom(&rom, Model::Dmg, &[0x31; 0x100])
+            .unwrap();
+        assert_eq!(emulator.program_counter, 0x0);
+        assert_eq!(emulator.stack_pointer, 0x0);
+        assert_eq!(emulator.read_memory(0x0).unwrap(), 0x31);
+        assert_eq!(emulator.read_memory(0x100).unwrap(), rom[0x100]);
+
+        emulator.set_memory(0xff50, 0x00).unwrap();
+        assert_eq!(emulator.read_memory(0x0).unwrap(), 0x31);
+        emulator.set_memory(0xff50, 0x01).unwrap();
+        assert_eq!(emulator.read_memory(0x0).unwrap(), rom[0x0]);
+    }
 }
@@ -0,0 +1,368 @@
+use util::*;
+use cpu::*;
+use state::*;
+use header::*;
+use cartridge::*;
+use emulator::*;
+use boot::*;
+
+pub const GBS_HEADER_SIZE: usize = 0x70;
+
+// The driver placed below the load address. Every interrupt vector calls the play routine, only
+// the one selected by the timer settings is enabled, and the init and play routines return to an
+// idle loop which waits for interrupts.
+const DRIVER_IDLE_ADDRESS: u16 = 0x0070;
+const DRIVER_PLAY_VECTORS: [u16; 2] = [0x0040, 0x0050];
+const DRIVER_RETI_VECTORS: [u16; 3] = [0x0048, 0x0058, 0x0060];
+
+/// The header of a .gbs Game Boy Sound file, which is followed by the music code and data.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub song_count: u8,
+    /// The song to play by default, counting from 1.
+    pub first_song: u8,
+    /// Where the code and data following the header are loaded, at least 0x400.
+    pub load_address: u16,
+    /// Called with the song number counting from 0 in A.
+    pub init_address: u16,
+    /// Called at the rate selected by the timer settings.
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    /// When bit 2 is set, the play routine is called from the timer interrupt with these TAC
+    /// settings, otherwise from the vblank interrupt. Bit 7 selects CGB double speed mode.
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+impl GbsHeader {
+    pub fn parse(gbs: &[u8]) -> Result<GbsHeader> {
+        if gbs.len() < GBS_HEADER_SIZE {
+            return Err(format!("gbs size invalid, {} bytes is too small to contain a header",
+                               gbs.len())
+                               .into());
+        }
+        if &gbs[0..3] != b"GBS" {
+            return Err("not a gbs file".into());
+        }
+
+        let word = |i: usize| make_word16(gbs[i + 1], gbs[i]);
+        let header = GbsHeader {
+            version: gbs[0x03],
+            song_count: gbs[0x04],
+            first_song: gbs[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0a),
+            stack_pointer: word(0x0c),
+            timer_modulo: gbs[0x0e],
+            timer_control: gbs[0x0f],
+            title: header_string(&gbs[0x10..0x30]),
+            author: header_string(&gbs[0x30..0x50]),
+            copyright: header_string(&gbs[0x50..0x70]),
+        };
+
+        if header.version != 1 {
+            return Err(format!("unsupported gbs version {}", header.version).into());
+        }
+        if header.song_count == 0 {
+            return Err("gbs file contains no songs".into());
+        }
+        if header.load_address < 0x400 || header.load_address >= 0x8000 {
+            return Err(format!("gbs load address {:x} is outside 0x400-0x7fff",
+                               header.load_address)
+                               .into());
+        }
+        Ok(header)
+    }
+
+    /// Whether the play routine is called from the timer interrupt rather than vblank.
+    pub fn uses_timer(&self) -> bool {
+        get_bit(self.timer_control, 2)
+    }
+}
+
+/// The code and data of a GBS file loaded at its load address, with ROM banks selected by writes
+/// to 0x2000-0x3fff and 8 KiB of RAM at 0xa000-0xbfff.
+pub struct GbsCartridge {
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    /// Never 0.
+    pub rom_bank: u8,
+}
+
+impl GbsCartridge {
+    pub fn new(header: &GbsHeader, data: &[u8]) -> GbsCartridge {
+        let load_address = header.load_address as usize;
+        // usize::div_ceil needs a newer compiler than the rest of the crate.
+        #[allow(clippy::manual_div_ceil)]
+        let len = (load_address + data.len() + 0x3fff) / 0x4000 * 0x4000;
+        let mut rom = vec![0x0; len];
+        rom[load_address..load_address + data.len()].copy_from_slice(data);
+
+        // The RST vectors jump to the same offset from the load address.
+        for rst in (0x00..0x40).step_by(8) {
+            let target = header.load_address + rst as u16;
+            rom[rst..rst + 3].copy_from_slice(&[0xc3, low_byte(target), high_byte(target)]);
+        }
+        for &vector in &DRIVER_PLAY_VECTORS {
+            let play = header.play_address;
+            rom[vector as usize..vector as usize + 4]
+                .copy_from_slice(&[0xcd, low_byte(play), high_byte(play), 0xd9]);
+        }
+        for &vector in &DRIVER_RETI_VECTORS {
+            rom[vector as usize] = 0xd9;
+        }
+        // EI, HALT, JR -4
+        let idle = DRIVER_IDLE_ADDRESS as usize;
+        rom[idle..idle + 4].copy_from_slice(&[0xfb, 0x76, 0x18, 0xfc]);
+
+        GbsCartridge {
+            rom,
+            ram: vec![0x0; 0x2000],
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Cartridge for GbsCartridge {
+    fn get_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_offset(&self.rom, bank as usize * 0x4000 + (addr as usize & 0x3fff))
+    }
+
+    fn write_rom(&mut self, addr: u16, n: u8) {
+        if let 0x2000...0x3fff = addr {
+            self.rom_bank = if n == 0 { 1 } else { n };
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[addr as usize - 0xa000]
+    }
+
+    fn write_ram(&mut self, addr: u16, n: u8) {
+        self.ram[addr as usize - 0xa000] = n;
+    }
+
+    fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn mapper_writes(&self) -> Vec<(u16, u8)> {
+        vec![(0x2000, self.rom_bank)]
+    }
+}
+
+impl SaveState for GbsCartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.ram)?;
+        self.rom_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+/// Plays the songs of a GBS file on an emulator with no cartridge other than the music code.
+pub struct GbsPlayer {
+    pub header: GbsHeader,
+    pub data: Vec<u8>,
+    pub sample_rate: u32,
+    /// Reset whenever a song is started.
+    pub emulator: Emulator,
+}
+
+impl GbsPlayer {
+    /// Loads a GBS file and starts its first song, producing samples at the given rate.
+    pub fn load(gbs: &[u8], sample_rate: u32) -> Result<GbsPlayer> {
+        let header = GbsHeader::parse(gbs)?;
+        let mut player = GbsPlayer {
+            data: gbs[GBS_HEADER_SIZE..].to_vec(),
+            sample_rate,
+            emulator: Emulator::new(),
+            header,
+        };
+        let first_song = player.header.first_song.saturating_sub(1);
+        player.start_song(first_song)?;
+        Ok(player)
+    }
+
+    /// Resets the emulator and calls the init routine for a song counting from 0, the play routine
+    /// then runs from interrupts.
+    pub fn start_song(&mut self, song: u8) -> Result<()> {
+        if song >= self.header.song_count {
+            return Err(format!("song {} out of range, the gbs file has {} songs",
+                               song,
+                               self.header.song_count)
+                               .into());
+        }
+
+        let header = &self.header;
+        let mut emulator = Emulator::new();
+        emulator.cartridge = Box::new(GbsCartridge::new(header, &self.data));
+        if get_bit(header.timer_control, 7) {
+            emulator.model = Model::Cgb;
+            emulator.cgb_mode = true;
+            emulator.double_speed = true;
+        }
+        emulator.set_sample_rate(self.sample_rate);
+
+        emulator.apu.set_register(0xff26, 0x80);
+        emulator.apu.set_register(0xff25, 0xff);
+        emulator.apu.set_register(0xff24, 0x77);
+
+        if header.uses_timer() {
+            emulator.timer.modulo = header.timer_modulo;
+            emulator.timer.counter = header.timer_modulo;
+            emulator.timer.control = header.timer_control & 0x07;
+            emulator.interrupt_enable = set_bit(0, TIMER_INTERRUPT, true);
+        } else {
+            emulator.ppu.set_lcd_control(0x80);
+            emulator.interrupt_enable = set_bit(0, VBLANK_INTERRUPT, true);
+        }
+
+        emulator.stack_pointer = header.stack_pointer;
+        push_stack16(&mut emulator, DRIVER_IDLE_ADDRESS)?;
+        emulator.a_register = song;
+        emulator.program_counter = header.init_address;
+
+        self.emulator = emulator;
+        Ok(())
+    }
+
+    /// Runs the current song until the buffer can be filled with interleaved left and right
+    /// samples.
+    pub fn render_i16(&mut self, buffer: &mut [i16]) -> Result<()> {
+        let mut read = 0;
+        while read < buffer.len() / 2 {
+            self.emulator.step()?;
+            read += self.emulator.read_samples_i16(&mut buffer[read * 2..]);
+        }
+        Ok(())
+    }
+
+    /// Runs the current song until the buffer can be filled with interleaved left and right
+    /// samples.
+    pub fn render_f32(&mut self, buffer: &mut [f32]) -> Result<()> {
+        let mut read = 0;
+        while read < buffer.len() / 2 {
+            self.emulator.step()?;
+            read += self.emulator.read_samples_f32(&mut buffer[read * 2..]);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A header loading at 0x400 with init at 0x400 and play at 0x401, followed by the data.
+    fn gbs_file(data: &[u8]) -> Vec<u8> {
+        let mut gbs = vec![0x0; GBS_HEADER_SIZE];
+        gbs[0..4].copy_from_slice(b"GBS\x01");
+        gbs[0x04] = 2;
+        gbs[0x05] = 1;
+        gbs[0x06..0x0e].copy_from_slice(&[0x00, 0x04, 0x00, 0x04, 0x01, 0x04, 0xfe, 0xff]);
+        gbs[0x10..0x15].copy_from_slice(b"Title");
+        gbs.extend_from_slice(data);
+        gbs
+    }
+
+    #[test]
+    fn parse_valid_header() {
+        let header = GbsHeader::parse(&gbs_file(&[])).unwrap();
+        assert_eq!(header.song_count, 2);
+        assert_eq!(header.first_song, 1);
+        assert_eq!(header.load_address, 0x400);
+        assert_eq!(header.play_address, 0x401);
+        assert_eq!(header.stack_pointer, 0xfffe);
+        assert_eq!(header.title, "Title");
+        assert!(!header.uses_timer());
+    }
+
+    #[test]
+    fn parse_rejects_invalid_headers() {
+        let gbs = gbs_file(&[]);
+        assert!(GbsHeader::parse(&gbs[..GBS_HEADER_SIZE - 1]).is_err());
+
+        let invalid: [(usize, u8); 5] = [(0x00, b'X'), (0x03, 2), (0x04, 0), (0x07, 0x03),
+                                         (0x07, 0x80)];
+        for &(offset, value) in &invalid {
+            let mut gbs = gbs.clone();
+            gbs[offset] = value;
+            assert!(GbsHeader::parse(&gbs).is_err(),
+                    "byte {:x} set to {:x}",
+                    offset,
+                    value);
+        }
+    }
+
+    #[test]
+    fn cartridge_layout() {
+        let header = GbsHeader::parse(&gbs_file(&[])).unwrap();
+        let cartridge = GbsCartridge::new(&header, &vec![0xaa; 0x3c00]);
+        assert_eq!(cartridge.rom.len(), 0x4000);
+        assert_eq!(&cartridge.rom[0x3ff..0x401], &[0x00, 0xaa]);
+        assert_eq!(cartridge.rom[0x3fff], 0xaa);
+
+        // Data crossing a bank boundary is padded to the end of the bank.
+        let cartridge = GbsCartridge::new(&header, &vec![0xaa; 0x3c01]);
+        assert_eq!(cartridge.rom.len(), 0x8000);
+
+        let rom = &cartridge.rom;
+        assert_eq!(&rom[0x00..0x03], &[0xc3, 0x00, 0x04]);
+        assert_eq!(&rom[0x38..0x3b], &[0xc3, 0x38, 0x04]);
+        assert_eq!(&rom[0x40..0x44], &[0xcd, 0x01, 0x04, 0xd9]);
+        assert_eq!(&rom[0x50..0x54], &[0xcd, 0x01, 0x04, 0xd9]);
+        for &vector in &DRIVER_RETI_VECTORS {
+            assert_eq!(rom[vector as usize], 0xd9);
+        }
+        assert_eq!(&rom[0x70..0x74], &[0xfb, 0x76, 0x18, 0xfc]);
+    }
+
+    #[test]
+    fn cartridge_bank_switching() {
+        let header = GbsHeader::parse(&gbs_file(&[])).unwrap();
+        let mut data = vec![0x0; 0x8000 - 0x400];
+        data[0x4000 - 0x400] = 1;
+        data[0x8000 - 0x400 - 1] = 2;
+        let mut cartridge = GbsCartridge::new(&header, &data);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+
+        cartridge.write_rom(0x2000, 0);
+        assert_eq!(cartridge.rom_bank, 1);
+        // Banks past the end wrap around.
+        cartridge.write_rom(0x3fff, 2);
+        assert_eq!(cartridge.read_rom(0x4000), 0xc3);
+        cartridge.write_rom(0x2000, 1);
+        assert_eq!(cartridge.read_rom(0x7fff), 2);
+    }
+
+    #[test]
+    fn renders_more_than_the_buffered_samples() {
+        // Init and play both return straight away.
+        let mut player = GbsPlayer::load(&gbs_file(&[0xc9, 0xc9]), 8000).unwrap();
+        let mut samples = vec![1; 8000 * 3];
+        player.render_i16(&mut samples).unwrap();
+        assert!(samples.iter().all(|&s| s == 0));
+
+        assert!(player.start_song(1).is_ok());
+        assert!(player.start_song(2).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use util::*;
+
+pub const HEADER_START: usize = 0x100;
+pub const HEADER_END: usize = 0x150;
+
+/// The CGB support declared at header byte 0x143.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    None,
+    /// Works on both DMG and CGB, 0x80
+    Compatible,
+    /// Only works on CGB, 0xc0
+    Only,
+}
+
+/// The decoded cartridge header at 0x100-0x14f.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CartridgeHeader {
+    /// The Nintendo logo at 0x104-0x133, which the boot ROM displays and verifies.
+    pub logo: [u8; 0x30],
+    /// Up to 16 characters at 0x134, shortened to 11 on carts with a manufacturer code.
+    pub title: String,
+    /// The 4 character code at 0x13f-0x142 present on later carts.
+    pub manufacturer_code: Option<String>,
+    pub cgb_flag: u8,
+    /// The 2 character code at 0x144-0x145, used when the old licensee code is 0x33.
+    pub new_licensee_code: String,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size: u8,
+    pub ram_size: u8,
+    /// 0x00 for Japan, 0x01 for everywhere else
+    pub destination_code: u8,
+    pub old_licensee_code: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+
+    /// The checksums computed from the ROM image, for comparison against the stored ones.
+    pub computed_header_checksum: u8,
+    pub computed_global_checksum: u16,
+    /// The sum of the 16 bytes of the title area, which the CGB boot ROM uses to choose a palette
+    /// for Nintendo's DMG games.
+    pub title_checksum: u8,
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader> {
+        if rom.len() < HEADER_END {
+            return Err(format!("rom size invalid, {} bytes is too small to contain a header",
+                               rom.len())
+                               .into());
+        }
+
+        let cgb_flag = rom[0x143];
+
+        // Carts with a manufacturer code have it in the last 4 bytes of the title area, it only
+        // exists alongside the CGB flag and is always upper case alphanumeric.
+        let manufacturer_code = &rom[0x13f..0x143];
+        let has_manufacturer_code = get_bit(cgb_flag, 7) &&
+                                    manufacturer_code
+                                        .iter()
+                                        .all(|&c| (c as char).is_ascii_uppercase() ||
+                                                  (c as char).is_ascii_digit());
+
+        let title_end = if has_manufacturer_code {
+            0x13f
+        } else if get_bit(cgb_flag, 7) {
+            0x143
+        } else {
+            0x144
+        };
+
+        let mut computed_header_checksum: u8 = 0;
+        for &b in &rom[0x134..0x14d] {
+            computed_header_checksum = computed_header_checksum.wrapping_sub(b).wrapping_sub(1);
+        }
+
+        let mut logo = [0x0; 0x30];
+        logo.copy_from_slice(&rom[0x104..0x134]);
+
+        let title_checksum = rom[0x134..0x144].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+
+        let mut computed_global_checksum: u16 = 0;
+        for (i, &b) in rom.iter().enumerate() {
+            if i != 0x14e && i != 0x14f {
+                computed_global_checksum = computed_global_checksum.wrapping_add(b as u16);
+            }
+        }
+
+        Ok(CartridgeHeader {
+               logo,
+               title: header_string(&rom[0x134..title_end]),
+               manufacturer_code: if has_manufacturer_code {
+                   Some(header_string(manufacturer_code))
+               } else {
+                   None
+               },
+               cgb_flag,
+               new_licensee_code: header_string(&rom[0x144..0x146]),
+               sgb_flag: rom[0x146],
+               cartridge_type: rom[0x147],
+               rom_size: rom[0x148],
+               ram_size: rom[0x149],
+               destination_code: rom[0x14a],
+               old_licensee_code: rom[0x14b],
+               version: rom[0x14c],
+               header_checksum: rom[0x14d],
+               global_checksum: make_word16(rom[0x14e], rom[0x14f]),
+               computed_header_checksum,
+               computed_global_checksum,
+               title_checksum,
+           })
+    }
+
+    pub fn cgb_support(&self) -> CgbSupport {
+        match self.cgb_flag {
+            0xc0 => CgbSupport::Only,
+            f if get_bit(f, 7) => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        }
+    }
+
+    /// SGB functions are only available when the SGB flag is 0x03 and the old licensee code is
+    /// 0x33.
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.old_licensee_code == 0x33
+    }
+
+    /// The licensee code as a 2 character string, taken from the new licensee code when the old one
+    /// is 0x33.
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == 0x33 {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+
+    /// Whether the cartridge type includes a battery, so its RAM and any clock should be persisted.
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type,
+                 0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff)
+    }
+
+    pub fn rom_size_bytes(&self) -> Option<usize> {
+        rom_size_bytes(self.rom_size)
+    }
+
+    pub fn ram_size_bytes(&self) -> Option<usize> {
+        ram_size_bytes(self.ram_size)
+    }
+
+    /// The boot ROM refuses to start a cart whose header checksum is wrong.
+    pub fn header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    /// The global checksum is not verified by hardware, but a mismatch indicates a bad dump.
+    pub fn global_checksum_valid(&self) -> bool {
+        self.global_checksum == self.computed_global_checksum
+    }
+}
+
+/// Returns the size in bytes of the ROM described by the rom_size code at header byte 0x148.
+pub fn rom_size_bytes(code: u8) -> Option<usize> {
+    match code {
+        0x00...0x08 => Some(0x8000 << code),
+        0x52 => Some(72 * 0x4000),
+        0x53 => Some(80 * 0x4000),
+        0x54 => Some(96 * 0x4000),
+        _ => None,
+    }
+}
+
+/// Returns the size in bytes of the external RAM described by the ram_size code at header byte
+/// 0x149.
+pub fn ram_size_bytes(code: u8) -> Option<usize> {
+    match code {
+        0x00 => Some(0),
+        0x01 => Some(0x800),
+        0x02 => Some(0x2000),
+        0x03 => Some(0x8000),
+        0x04 => Some(0x20000),
+        0x05 => Some(0x10000),
+        _ => None,
+    }
+}
+
+/// Decodes a NUL padded ASCII header string, replacing unprintable characters.
+pub fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if c.is_ascii_graphic() || c == b' ' {
+                 c as char
+             } else {
+                 '?'
+             })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 32 KiB ROM with the given title area and CGB flag, and correct checksums. A 16 byte title
+    // also fills the CGB flag byte.
+    fn header_rom(title: &[u8], cgb_flag: u8) -> Vec<u8> {
+        let mut rom = vec![0x0; 0x8000];
+        rom[0x143] = cgb_flag;
+        rom[0x134..0x134 + title.len()].copy_from_slice(title);
+        rom[0x147] = 0x01;
+        rom[0x14b] = 0x33;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x200] = 0x5a;
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    fn fix_checksums(rom: &mut [u8]) {
+        let mut header_checksum: u8 = 0;
+        for &b in &rom[0x134..0x14d] {
+            header_checksum = header_checksum.wrapping_sub(b).wrapping_sub(1);
+        }
+        rom[0x14d] = header_checksum;
+
+        let mut global_checksum: u16 = 0;
+        for (i, &b) in rom.iter().enumerate() {
+            if i != 0x14e && i != 0x14f {
+                global_checksum = global_checksum.wrapping_add(b as u16);
+            }
+        }
+        rom[0x14e] = high_byte(global_checksum);
+        rom[0x14f] = low_byte(global_checksum);
+    }
+
+    #[test]
+    fn parse_valid_header() {
+        let rom = header_rom(b"TEST GAME", 0x00);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TEST GAME");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.cgb_support(), CgbSupport::None);
+        assert_eq!(header.cartridge_type, 0x01);
+        assert_eq!(header.licensee_code(), "01");
+        assert_eq!(header.rom_size_bytes(), Some(0x8000));
+        assert_eq!(header.ram_size_bytes(), Some(0));
+        assert!(header.header_checksum_valid());
+        assert!(header.global_checksum_valid());
+    }
+
+    #[test]
+    fn parse_rejects_truncated_rom() {
+        assert!(CartridgeHeader::parse(&[0x0; HEADER_END - 1]).is_err());
+    }
+
+    #[test]
+    fn corrupted_header_checksum() {
+        let mut rom = header_rom(b"TEST GAME", 0x00);
+        rom[0x14d] = rom[0x14d].wrapping_add(1);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.header_checksum_valid());
+    }
+
+    #[test]
+    fn wrong_global_checksum() {
+        let mut rom = header_rom(b"TEST GAME", 0x00);
+        rom[0x4000] = 0x01;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(header.header_checksum_valid());
+        assert!(!header.global_checksum_valid());
+        assert_eq!(header.computed_global_checksum, header.global_checksum.wrapping_add(1));
+    }
+
+    #[test]
+    fn title_decoding() {
+        // DMG carts use all 16 bytes for the title.
+        let header = CartridgeHeader::parse(&header_rom(b"SIXTEEN CHARS XY", 0x00)).unwrap();
+        assert_eq!(header.title, "SIXTEEN CHARS XY");
+
+        // The CGB flag takes the last byte.
+        let header = CartridgeHeader::parse(&header_rom(b"FIFTEEN CHARS X", 0x80)).unwrap();
+        assert_eq!(header.title, "FIFTEEN CHARS X");
+        assert_eq!(header.manufacturer_code, None);
+        assert_eq!(header.cgb_support(), CgbSupport::Compatible);
+
+        // A manufacturer code takes another 4.
+        let header = CartridgeHeader::parse(&header_rom(b"ELEVEN CHARAB1E", 0xc0)).unwrap();
+        assert_eq!(header.title, "ELEVEN CHAR");
+        assert_eq!(header.manufacturer_code, Some("AB1E".to_string()));
+        assert_eq!(header.cgb_support(), CgbSupport::Only);
+
+        // Lower case bytes can't be a manufacturer code, so they stay part of the title.
+        let header = CartridgeHeader::parse(&header_rom(b"ELEVEN CHARabcd", 0x80)).unwrap();
+        assert_eq!(header.title, "ELEVEN CHARabcd");
+        assert_eq!(header.manufacturer_code, None);
+
+        // Titles end at the first NUL and unprintable characters are replaced.
+        let header = CartridgeHeader::parse(&header_rom(b"BAD\x01NAME\x00JUNK", 0x00)).unwrap();
+        assert_eq!(header.title, "BAD?NAME");
+    }
+}
@@ -0,0 +1,102 @@
+use util::*;
+use cpu::*;
+use state::*;
+
+/// The set of buttons currently held down.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Buttons {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+
+pub struct Joypad {
+    pub buttons: Buttons,
+    /// P1 bits 4 and 5, when bit 4 is low the direction keys are selected and when bit 5 is low the
+    /// action buttons are selected.
+    pub select: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            buttons: Buttons::default(),
+            select: 0x30,
+        }
+    }
+
+    /// Reads P1 (0xff00), pressed buttons on the selected lines read as 0.
+    pub fn get_p1(&self) -> u8 {
+        0xc0 | self.select | self.get_lines()
+    }
+
+    /// Returns the bits of any interrupts that should be requested.
+    pub fn set_p1(&mut self, n: u8) -> u8 {
+        let old_lines = self.get_lines();
+        self.select = n & 0x30;
+        self.interrupts(old_lines)
+    }
+
+    /// Returns the bits of any interrupts that should be requested.
+    pub fn set_buttons(&mut self, buttons: Buttons) -> u8 {
+        let old_lines = self.get_lines();
+        self.buttons = buttons;
+        self.interrupts(old_lines)
+    }
+
+    /// The lower 4 bits of P1, a line is low when any selected button on it is pressed.
+    pub fn get_lines(&self) -> u8 {
+        let buttons = &self.buttons;
+        let mut lines = 0x0f;
+        if !get_bit(self.select, 4) {
+            lines &= !button_lines(buttons.right, buttons.left, buttons.up, buttons.down);
+        }
+        if !get_bit(self.select, 5) {
+            lines &= !button_lines(buttons.a, buttons.b, buttons.select, buttons.start);
+        }
+        lines
+    }
+
+    // The joypad interrupt is requested when any of the input lines goes from high to low.
+    fn interrupts(&self, old_lines: u8) -> u8 {
+        if old_lines & !self.get_lines() != 0 {
+            set_bit(0, JOYPAD_INTERRUPT, true)
+        } else {
+            0
+        }
+    }
+}
+
+fn button_lines(line0: bool, line1: bool, line2: bool, line3: bool) -> u8 {
+    (line3 as u8) << 3 | (line2 as u8) << 2 | (line1 as u8) << 1 | line0 as u8
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let buttons = &self.buttons;
+        writer.write_u8(button_lines(buttons.right, buttons.left, buttons.up, buttons.down) |
+                        button_lines(buttons.a, buttons.b, buttons.select, buttons.start) << 4);
+        writer.write_u8(self.select);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let buttons = reader.read_u8()?;
+        self.buttons = Buttons {
+            right: get_bit(buttons, 0),
+            left: get_bit(buttons, 1),
+            up: get_bit(buttons, 2),
+            down: get_bit(buttons, 3),
+            a: get_bit(buttons, 4),
+            b: get_bit(buttons, 5),
+            select: get_bit(buttons, 6),
+            start: get_bit(buttons, 7),
+        };
+        self.select = reader.read_u8()?;
+        Ok(())
+    }
+}
@@ -1,6 +1,23 @@
 pub mod util;
+pub mod state;
 pub mod instruction;
 pub mod decoding;
 pub mod cpu;
 pub mod screen;
+pub mod ppu;
+pub mod timer;
+pub mod joypad;
+pub mod dma;
+pub mod apu;
+pub mod audio;
+pub mod wav;
+pub mod gbs;
+pub mod header;
+pub mod cartridge;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
 pub mod emulator;
+pub mod boot;
+pub mod bess;
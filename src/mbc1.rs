@@ -0,0 +1,157 @@
+use util::*;
+use cartridge::*;
+use state::*;
+
+/// The MBC1 memory bank controller, with up to 2 MiB of ROM and 32 KiB of RAM.
+pub struct Mbc1 {
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+
+    /// Set by writing 0x0a to 0x0000-0x1fff, external ram is inaccessible otherwise.
+    pub ram_enabled: bool,
+    /// The 5 bit ROM bank register written at 0x2000-0x3fff, never 0.
+    pub rom_bank: u8,
+    /// The 2 bit secondary register written at 0x4000-0x5fff, selects the RAM bank or the upper
+    /// ROM bank bits.
+    pub secondary_bank: u8,
+    /// Banking mode written at 0x6000-0x7fff, when set the secondary register also applies to
+    /// 0x0000-0x3fff and to external ram.
+    pub advanced_banking: bool,
+    /// MBC1M multicart wiring, where the secondary register is shifted into ROM bank bits 4-5
+    /// instead of 5-6, and bit 4 of the ROM bank register is unconnected.
+    pub multicart: bool,
+}
+
+impl Mbc1 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>) -> Mbc1 {
+        let multicart = Mbc1::detect_multicart(&rom);
+        Mbc1 {
+            rom,
+            ram,
+            ram_enabled: false,
+            rom_bank: 1,
+            secondary_bank: 0,
+            advanced_banking: false,
+            multicart,
+        }
+    }
+
+    /// MBC1M carts are all 8 Mbit and have a second copy of the Nintendo logo in the header of the
+    /// game at bank 0x10.
+    pub fn detect_multicart(rom: &[u8]) -> bool {
+        rom.len() == 0x100000 && rom[0x104..0x134] == rom[0x40104..0x40134]
+    }
+
+    fn write_register(&mut self, addr: u16, n: u8) {
+        match addr {
+            0x0000...0x1fff => self.ram_enabled = low_nibble(n) == 0x0a,
+            0x2000...0x3fff => {
+                // The zero check happens on all 5 bits even on multicart wiring
+                self.rom_bank = n & 0x1f;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000...0x5fff => self.secondary_bank = n & 0x03,
+            _ => self.advanced_banking = get_bit(n, 0),
+        }
+    }
+
+    // Returns the offset into the full ROM image for an address in 0x0000-0x7fff, before masking to
+    // the size of the ROM.
+    fn rom_offset(&self, addr: u16) -> usize {
+        let (shift, low_bank) = if self.multicart {
+            (4, self.rom_bank & 0x0f)
+        } else {
+            (5, self.rom_bank)
+        };
+
+        let bank = if addr < 0x4000 {
+            if self.advanced_banking {
+                self.secondary_bank << shift
+            } else {
+                0
+            }
+        } else {
+            self.secondary_bank << shift | low_bank
+        };
+
+        bank as usize * 0x4000 + (addr as usize & 0x3fff)
+    }
+
+    // Returns the offset into external ram for an address in 0xa000-0xbfff, or None if ram is
+    // disabled or absent.
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+
+        let bank = if self.advanced_banking {
+            self.secondary_bank
+        } else {
+            0
+        };
+        Some((bank as usize * 0x2000 + (addr as usize - 0xa000)) % self.ram.len())
+    }
+}
+
+impl Cartridge for Mbc1 {
+    fn get_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        read_rom_offset(&self.rom, self.rom_offset(addr))
+    }
+
+    fn write_rom(&mut self, addr: u16, n: u8) {
+        self.write_register(addr, n);
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram_offset(addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, n: u8) {
+        if let Some(offset) = self.ram_offset(addr) {
+            self.ram[offset] = n;
+        }
+    }
+
+    fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn mapper_writes(&self) -> Vec<(u16, u8)> {
+        vec![(0x0000, if self.ram_enabled { 0x0a } else { 0x00 }),
+             (0x2000, self.rom_bank),
+             (0x4000, self.secondary_bank),
+             (0x6000, self.advanced_banking as u8)]
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.secondary_bank);
+        writer.write_bool(self.advanced_banking);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        self.secondary_bank = reader.read_u8()?;
+        self.advanced_banking = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,95 @@
+use util::*;
+use cartridge::*;
+use state::*;
+
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+/// The MBC2 memory bank controller, with up to 256 KiB of ROM and a built in 512x4 bit RAM.
+pub struct Mbc2 {
+    pub rom: Vec<u8>,
+    /// One byte per 4 bit RAM cell, only the lower nibble is stored.
+    pub ram: Vec<u8>,
+
+    pub ram_enabled: bool,
+    /// The 4 bit ROM bank register, never 0.
+    pub rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new(rom: Vec<u8>) -> Mbc2 {
+        Mbc2 {
+            rom,
+            ram: vec![0x0; MBC2_RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Cartridge for Mbc2 {
+    fn get_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_offset(&self.rom, bank as usize * 0x4000 + (addr as usize & 0x3fff))
+    }
+
+    fn write_rom(&mut self, addr: u16, n: u8) {
+        // Both registers live in 0x0000-0x3fff, address bit 8 selects between them.
+        if addr >= 0x4000 {
+            return;
+        }
+
+        if get_bit(addr, 8) {
+            self.rom_bank = low_nibble(n);
+            if self.rom_bank == 0 {
+                self.rom_bank = 1;
+            }
+        } else {
+            self.ram_enabled = low_nibble(n) == 0x0a;
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        // The RAM echoes throughout 0xa000-0xbfff and the upper 4 bits are not connected.
+        0xf0 | self.ram[addr as usize % MBC2_RAM_SIZE]
+    }
+
+    fn write_ram(&mut self, addr: u16, n: u8) {
+        if self.ram_enabled {
+            self.ram[addr as usize % MBC2_RAM_SIZE] = low_nibble(n);
+        }
+    }
+
+    fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn mapper_writes(&self) -> Vec<(u16, u8)> {
+        vec![(0x0000, if self.ram_enabled { 0x0a } else { 0x00 }), (0x0100, self.rom_bank)]
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        Ok(())
+    }
+}
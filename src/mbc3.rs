@@ -0,0 +1,515 @@
+use util::*;
+use cartridge::*;
+use state::*;
+
+/// The number of clocks in one second of emulated time, the RTC crystal is independent of the
+/// cpu speed.
+pub const CLOCKS_PER_SECOND: u32 = 4194304;
+
+/// The RTC footer appended to RAM in .sav files by other emulators, 5 current and 5 latched
+/// registers as little endian u32s followed by a little endian u64 unix timestamp. Some emulators
+/// write a 32 bit timestamp instead.
+pub const RTC_FOOTER_SIZE: usize = 48;
+pub const RTC_FOOTER_SIZE_SHORT: usize = 44;
+
+/// The MBC3 real time clock registers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RtcRegisters {
+    pub seconds: u8,
+    pub minutes: u8,
+    pub hours: u8,
+    /// The 9 bit day counter
+    pub days: u16,
+    pub halted: bool,
+    /// Set when the day counter overflows, only cleared by writing to DH.
+    pub day_carry: bool,
+}
+
+impl RtcRegisters {
+    /// Reads the register selected by a RAM bank number from 0x08 to 0x0c.
+    pub fn get_register(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0a => self.hours,
+            0x0b => low_byte(self.days),
+            _ => {
+                let mut dh = high_byte(self.days) & 0x01;
+                dh = set_bit(dh, 6, self.halted);
+                dh = set_bit(dh, 7, self.day_carry);
+                dh
+            }
+        }
+    }
+
+    pub fn set_register(&mut self, register: u8, n: u8) {
+        match register {
+            0x08 => self.seconds = n & 0x3f,
+            0x09 => self.minutes = n & 0x3f,
+            0x0a => self.hours = n & 0x1f,
+            0x0b => self.days = make_word16(high_byte(self.days), n),
+            _ => {
+                self.days = make_word16(n & 0x01, low_byte(self.days));
+                self.halted = get_bit(n, 6);
+                self.day_carry = get_bit(n, 7);
+            }
+        }
+    }
+
+    /// Advances the clock by the given number of seconds, unless it is halted.
+    pub fn advance(&mut self, seconds: u64) {
+        if self.halted {
+            return;
+        }
+        // Whole days can be skipped at once, days past the 9 bit counter only matter for the carry.
+        let (days, seconds) = (seconds / 86400, seconds % 86400);
+        let days = self.days as u64 + days;
+        if days >= 0x200 {
+            self.day_carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+        for _ in 0..seconds {
+            self.advance_second();
+        }
+    }
+
+    /// Advances the clock by one second. Out of range values written by the game keep counting up
+    /// to the limit of their bit width and then wrap to 0 without carrying.
+    pub fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+
+        self.days += 1;
+        if self.days == 0x200 {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+}
+
+/// The MBC3 memory bank controller, with up to 2 MiB of ROM, 32 KiB of RAM and an optional real
+/// time clock.
+pub struct Mbc3 {
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    pub has_rtc: bool,
+
+    /// Enables both external RAM and the RTC registers.
+    pub ram_enabled: bool,
+    /// The 7 bit ROM bank register, never 0.
+    pub rom_bank: u8,
+    /// Selects RAM banks 0x00-0x03, or the RTC registers 0x08-0x0c.
+    pub ram_bank: u8,
+    /// The last value written to 0x6000-0x7fff, writing 0x00 then 0x01 latches the clock.
+    pub latch_write: u8,
+
+    pub rtc: RtcRegisters,
+    /// The copy of the RTC registers visible to the game, updated on latch.
+    pub latched_rtc: RtcRegisters,
+    /// Clocks counted towards the next RTC second when running from emulated cycles.
+    pub rtc_clocks: u32,
+    /// When set, the RTC follows this clock rather than emulated cycles.
+    pub clock_source: Option<Box<dyn ClockSource>>,
+    /// The clock source time the RTC was last advanced to, without a clock source this is the
+    /// timestamp of the last loaded battery footer.
+    pub clock_source_time: u64,
+}
+
+impl Mbc3 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>, has_rtc: bool) -> Mbc3 {
+        Mbc3 {
+            rom,
+            ram,
+            has_rtc,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch_write: 0xff,
+            rtc: RtcRegisters::default(),
+            latched_rtc: RtcRegisters::default(),
+            rtc_clocks: 0,
+            clock_source: None,
+            clock_source_time: 0,
+        }
+    }
+
+    // Advances the RTC to the current time of the clock source, if there is one.
+    fn sync_clock_source(&mut self) {
+        if let Some(ref clock_source) = self.clock_source {
+            let now = clock_source.now();
+            let elapsed = now.saturating_sub(self.clock_source_time);
+            self.clock_source_time = now;
+            self.rtc.advance(elapsed);
+        }
+    }
+
+    // Returns the RTC register selected by the RAM bank register, if any.
+    fn rtc_register(&self) -> Option<u8> {
+        if self.has_rtc && self.ram_bank >= 0x08 && self.ram_bank <= 0x0c {
+            Some(self.ram_bank)
+        } else {
+            None
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if self.ram.is_empty() || self.ram_bank > 0x03 {
+            return None;
+        }
+        Some((self.ram_bank as usize * 0x2000 + (addr as usize - 0xa000)) % self.ram.len())
+    }
+}
+
+impl Cartridge for Mbc3 {
+    fn get_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_offset(&self.rom, bank as usize * 0x4000 + (addr as usize & 0x3fff))
+    }
+
+    fn write_rom(&mut self, addr: u16, n: u8) {
+        match addr {
+            0x0000...0x1fff => self.ram_enabled = low_nibble(n) == 0x0a,
+            0x2000...0x3fff => {
+                self.rom_bank = n & 0x7f;
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1;
+                }
+            }
+            0x4000...0x5fff => self.ram_bank = n,
+            _ => {
+                if self.latch_write == 0x00 && n == 0x01 {
+                    self.sync_clock_source();
+                    self.latched_rtc = self.rtc;
+                }
+                self.latch_write = n;
+            }
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+
+        if let Some(register) = self.rtc_register() {
+            self.latched_rtc.get_register(register)
+        } else if let Some(offset) = self.ram_offset(addr) {
+            self.ram[offset]
+        } else {
+            0xff
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, n: u8) {
+        if !self.ram_enabled {
+            return;
+        }
+
+        if let Some(register) = self.rtc_register() {
+            self.sync_clock_source();
+            if register == 0x08 {
+                self.rtc_clocks = 0;
+            }
+            self.rtc.set_register(register, n);
+            self.latched_rtc.set_register(register, n);
+        } else if let Some(offset) = self.ram_offset(addr) {
+            self.ram[offset] = n;
+        }
+    }
+
+    fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn mapper_writes(&self) -> Vec<(u16, u8)> {
+        vec![(0x0000, if self.ram_enabled { 0x0a } else { 0x00 }),
+             (0x2000, self.rom_bank),
+             (0x4000, self.ram_bank)]
+    }
+
+    fn save_battery_footer(&self) -> Vec<u8> {
+        if !self.has_rtc {
+            return Vec::new();
+        }
+
+        // A clock source only advances the RTC when it is accessed, so the registers are brought up
+        // to the timestamp written with them. Without one the RTC follows emulated cycles, and the
+        // timestamp is the last one synced so that saving never depends on the host's clock.
+        let mut rtc = self.rtc;
+        let mut timestamp = self.clock_source_time;
+        if let Some(ref clock_source) = self.clock_source {
+            timestamp = clock_source.now();
+            rtc.advance(timestamp.saturating_sub(self.clock_source_time));
+        }
+
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        for rtc in &[rtc, self.latched_rtc] {
+            for register in 0x08..0x0d {
+                footer.extend_from_slice(&(rtc.get_register(register) as u32).to_le_bytes());
+            }
+        }
+        footer.extend_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    fn load_battery_footer(&mut self, footer: &[u8]) -> Result<()> {
+        if !self.has_rtc || footer.is_empty() {
+            return Ok(());
+        }
+
+        if footer.len() != RTC_FOOTER_SIZE && footer.len() != RTC_FOOTER_SIZE_SHORT {
+            return Err(format!("invalid rtc footer size {}", footer.len()).into());
+        }
+
+        let word = |i: usize| footer[i * 4];
+        for register in 0x08..0x0d {
+            self.rtc.set_register(register, word(register as usize - 0x08));
+            self.latched_rtc.set_register(register, word(register as usize - 0x03));
+        }
+
+        let mut timestamp = [0; 8];
+        timestamp[..footer.len() - 40].copy_from_slice(&footer[40..]);
+        let timestamp = u64::from_le_bytes(timestamp);
+
+        // Time passes while the game is not running, but only when following a real clock.
+        match self.clock_source {
+            Some(ref clock_source) => {
+                let now = clock_source.now();
+                self.rtc.advance(now.saturating_sub(timestamp));
+                self.clock_source_time = now;
+            }
+            None => self.clock_source_time = timestamp,
+        }
+        self.rtc_clocks = 0;
+        Ok(())
+    }
+
+    fn tick(&mut self, clocks: u8) {
+        if !self.has_rtc || self.rtc.halted || self.clock_source.is_some() {
+            return;
+        }
+
+        self.rtc_clocks += clocks as u32;
+        if self.rtc_clocks >= CLOCKS_PER_SECOND {
+            self.rtc_clocks -= CLOCKS_PER_SECOND;
+            self.rtc.advance_second();
+        }
+    }
+
+    fn set_clock_source(&mut self, clock_source: Box<dyn ClockSource>) {
+        self.clock_source_time = clock_source.now();
+        self.clock_source = Some(clock_source);
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for register in 0x08..0x0d {
+            writer.write_u8(self.get_register(register));
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        for register in 0x08..0x0d {
+            let n = reader.read_u8()?;
+            self.set_register(register, n);
+        }
+        Ok(())
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_u8(self.latch_write);
+        self.rtc.save_state(writer);
+        self.latched_rtc.save_state(writer);
+        writer.write_u32(self.rtc_clocks);
+        writer.write_u64(self.clock_source_time);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u8()?;
+        self.ram_bank = reader.read_u8()?;
+        self.latch_write = reader.read_u8()?;
+        self.rtc.load_state(reader)?;
+        self.latched_rtc.load_state(reader)?;
+        self.rtc_clocks = reader.read_u32()?;
+        self.clock_source_time = reader.read_u64()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    // A clock which only moves when the test advances it.
+    struct FakeClock(Rc<Cell<u64>>);
+
+    impl ClockSource for FakeClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    fn mbc3_with_clock(start: u64) -> (Mbc3, Rc<Cell<u64>>) {
+        let time = Rc::new(Cell::new(start));
+        let mut mbc3 = Mbc3::new(vec![0x0; 0x8000], vec![0x0; 0x2000], true);
+        mbc3.set_clock_source(Box::new(FakeClock(time.clone())));
+        mbc3.write_rom(0x0000, 0x0a);
+        (mbc3, time)
+    }
+
+    fn latch(mbc3: &mut Mbc3) {
+        mbc3.write_rom(0x6000, 0x00);
+        mbc3.write_rom(0x6000, 0x01);
+    }
+
+    fn read_rtc(mbc3: &mut Mbc3, register: u8) -> u8 {
+        mbc3.write_rom(0x4000, register);
+        mbc3.read_ram(0xa000)
+    }
+
+    #[test]
+    fn battery_footer_includes_time_since_last_latch() {
+        let (mbc3, time) = mbc3_with_clock(1000);
+        time.set(1000 + 3600);
+        let footer = mbc3.save_battery_footer();
+
+        let (mut restored, _) = mbc3_with_clock(1000 + 3600);
+        restored.load_battery_footer(&footer).unwrap();
+        latch(&mut restored);
+        assert_eq!(read_rtc(&mut restored, 0x0a), 1);
+        assert_eq!(read_rtc(&mut restored, 0x09), 0);
+    }
+
+    #[test]
+    fn battery_footer_without_clock_source_keeps_loaded_timestamp() {
+        let (mbc3, _) = mbc3_with_clock(1234);
+        let footer = mbc3.save_battery_footer();
+
+        let mut restored = Mbc3::new(vec![0x0; 0x8000], vec![0x0; 0x2000], true);
+        assert_eq!(&restored.save_battery_footer()[40..], &[0x0; 8]);
+        restored.load_battery_footer(&footer).unwrap();
+        assert_eq!(restored.save_battery_footer(), footer);
+    }
+
+    #[test]
+    fn battery_footer_does_not_advance_halted_clock() {
+        let (mut mbc3, time) = mbc3_with_clock(0);
+        mbc3.write_rom(0x4000, 0x0c);
+        mbc3.write_ram(0xa000, 0x40);
+        time.set(3600);
+        let footer = mbc3.save_battery_footer();
+        assert_eq!(footer[8], 0);
+    }
+
+    #[test]
+    fn latch_requires_0x00_then_0x01() {
+        let (mut mbc3, time) = mbc3_with_clock(0);
+        time.set(65);
+        mbc3.write_rom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut mbc3, 0x08), 0);
+
+        latch(&mut mbc3);
+        assert_eq!(read_rtc(&mut mbc3, 0x08), 5);
+        assert_eq!(read_rtc(&mut mbc3, 0x09), 1);
+
+        // The latched registers stay put until the next latch.
+        time.set(70);
+        assert_eq!(read_rtc(&mut mbc3, 0x08), 5);
+        latch(&mut mbc3);
+        assert_eq!(read_rtc(&mut mbc3, 0x08), 10);
+    }
+
+    #[test]
+    fn halt_stops_the_clock() {
+        let (mut mbc3, time) = mbc3_with_clock(0);
+        mbc3.write_rom(0x4000, 0x0c);
+        mbc3.write_ram(0xa000, 0x40);
+        time.set(100);
+        latch(&mut mbc3);
+        assert_eq!(read_rtc(&mut mbc3, 0x08), 0);
+        assert_eq!(read_rtc(&mut mbc3, 0x0c), 0x40);
+
+        mbc3.write_rom(0x4000, 0x0c);
+        mbc3.write_ram(0xa000, 0x00);
+        time.set(110);
+        latch(&mut mbc3);
+        assert_eq!(read_rtc(&mut mbc3, 0x08), 10);
+    }
+
+    #[test]
+    fn day_counter_overflow_sets_carry() {
+        let (mut mbc3, time) = mbc3_with_clock(0);
+        mbc3.write_rom(0x4000, 0x0b);
+        mbc3.write_ram(0xa000, 0xff);
+        mbc3.write_rom(0x4000, 0x0c);
+        mbc3.write_ram(0xa000, 0x01);
+
+        time.set(86400);
+        latch(&mut mbc3);
+        assert_eq!(read_rtc(&mut mbc3, 0x0b), 0x00);
+        assert_eq!(read_rtc(&mut mbc3, 0x0c), 0x80);
+
+        // The carry stays set until written.
+        time.set(2 * 86400);
+        latch(&mut mbc3);
+        assert_eq!(read_rtc(&mut mbc3, 0x0b), 0x01);
+        assert_eq!(read_rtc(&mut mbc3, 0x0c), 0x80);
+        mbc3.write_ram(0xa000, 0x00);
+        assert_eq!(read_rtc(&mut mbc3, 0x0c), 0x00);
+    }
+
+    #[test]
+    fn advance_across_several_days() {
+        let mut rtc = RtcRegisters {
+            seconds: 59,
+            minutes: 59,
+            hours: 23,
+            days: 2,
+            ..RtcRegisters::default()
+        };
+        rtc.advance(3 * 86400 + 3600 + 2 * 60 + 3);
+        assert_eq!(rtc,
+                   RtcRegisters {
+                       seconds: 2,
+                       minutes: 2,
+                       hours: 1,
+                       days: 6,
+                       ..RtcRegisters::default()
+                   });
+    }
+}
@@ -0,0 +1,124 @@
+use util::*;
+use cartridge::*;
+use state::*;
+
+/// The MBC5 memory bank controller, with up to 8 MiB of ROM, 128 KiB of RAM and an optional rumble
+/// motor.
+pub struct Mbc5 {
+    pub rom: Vec<u8>,
+    pub ram: Vec<u8>,
+    pub has_rumble: bool,
+
+    pub ram_enabled: bool,
+    /// The 9 bit ROM bank register, unlike earlier mappers bank 0 can be selected.
+    pub rom_bank: u16,
+    /// The 4 bit RAM bank register, only 3 bits wide on rumble carts.
+    pub ram_bank: u8,
+    /// On rumble carts, bit 3 of the RAM bank register drives the motor.
+    pub rumble: bool,
+}
+
+impl Mbc5 {
+    pub fn new(rom: Vec<u8>, ram: Vec<u8>, has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            rom,
+            ram,
+            has_rumble,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble: false,
+        }
+    }
+
+    fn ram_offset(&self, addr: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        Some((self.ram_bank as usize * 0x2000 + (addr as usize - 0xa000)) % self.ram.len())
+    }
+}
+
+impl Cartridge for Mbc5 {
+    fn get_rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_offset(&self.rom, bank as usize * 0x4000 + (addr as usize & 0x3fff))
+    }
+
+    fn write_rom(&mut self, addr: u16, n: u8) {
+        match addr {
+            0x0000...0x1fff => self.ram_enabled = n == 0x0a,
+            0x2000...0x2fff => self.rom_bank = make_word16(high_byte(self.rom_bank), n),
+            0x3000...0x3fff => self.rom_bank = make_word16(n & 0x01, low_byte(self.rom_bank)),
+            0x4000...0x5fff => {
+                if self.has_rumble {
+                    self.ram_bank = n & 0x07;
+                    self.rumble = get_bit(n, 3);
+                } else {
+                    self.ram_bank = n & 0x0f;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        match self.ram_offset(addr) {
+            Some(offset) => self.ram[offset],
+            None => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, n: u8) {
+        if let Some(offset) = self.ram_offset(addr) {
+            self.ram[offset] = n;
+        }
+    }
+
+    fn get_ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    fn get_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    fn mapper_writes(&self) -> Vec<(u16, u8)> {
+        let ram_bank = if self.has_rumble {
+            set_bit(self.ram_bank, 3, self.rumble)
+        } else {
+            self.ram_bank
+        };
+        vec![(0x0000, if self.ram_enabled { 0x0a } else { 0x00 }),
+             (0x2000, low_byte(self.rom_bank)),
+             (0x3000, high_byte(self.rom_bank)),
+             (0x4000, ram_bank)]
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.rumble);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        self.ram_bank = reader.read_u8()?;
+        self.rumble = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use util::*;
+use cpu::*;
+use screen::*;
+use state::*;
+
+pub const DOTS_PER_LINE: u16 = 456;
+pub const LINES_PER_FRAME: u8 = 154;
+pub const DOTS_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+
+const OAM_SEARCH_DOTS: u16 = 80;
+const PIXEL_TRANSFER_DOTS: u16 = 172;
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank,
+    VBlank,
+    OamSearch,
+    PixelTransfer,
+}
+
+pub struct Ppu {
+    pub character_ram: [u8; 0x1800],
+    pub bg_map_data: [u8; 0x800],
+    pub sprite_attribute_data: [u8; 0xa0],
+
+    /// LCDC (0xff40)
+    pub lcd_control: u8,
+    /// The writable interrupt select bits 3-6 of STAT (0xff41)
+    pub stat_interrupt_select: u8,
+    /// SCY (0xff42)
+    pub scroll_y: u8,
+    /// SCX (0xff43)
+    pub scroll_x: u8,
+    /// LY (0xff44)
+    pub line: u8,
+    /// LYC (0xff45)
+    pub line_compare: u8,
+    /// BGP (0xff47)
+    pub bg_palette: u8,
+    /// OBP0 (0xff48)
+    pub object_palette0: u8,
+    /// OBP1 (0xff49)
+    pub object_palette1: u8,
+    /// WY (0xff4a)
+    pub window_y: u8,
+    /// WX (0xff4b)
+    pub window_x: u8,
+
+    pub mode: Mode,
+    /// The dot within the current line, from 0 to DOTS_PER_LINE - 1
+    pub line_dot: u16,
+    /// Counts dots while the lcd is off, so that blank frames are still produced at the normal
+    /// rate.
+    pub disabled_dots: u32,
+    /// The STAT interrupt is only requested on a rising edge of the OR of all the selected STAT
+    /// conditions.
+    pub stat_line: bool,
+    /// Set once LY has matched WY during the current frame, the window is not drawn before then.
+    pub window_y_triggered: bool,
+    /// The internal window line counter, only advanced on lines where the window was drawn.
+    pub window_line: u8,
+
+    /// The frame currently being drawn
+    pub screen: Screen,
+    /// The last completed frame
+    pub frame: Screen,
+    /// Incremented every time a frame is completed
+    pub frame_count: u64,
+}
+
+impl Ppu {
+    pub fn new() -> Ppu {
+        Ppu {
+            character_ram: [0x0; 0x1800],
+            bg_map_data: [0x0; 0x800],
+            sprite_attribute_data: [0x0; 0xa0],
+            lcd_control: 0x0,
+            stat_interrupt_select: 0x0,
+            scroll_y: 0x0,
+            scroll_x: 0x0,
+            line: 0x0,
+            line_compare: 0x0,
+            bg_palette: 0x0,
+            object_palette0: 0x0,
+            object_palette1: 0x0,
+            window_y: 0x0,
+            window_x: 0x0,
+            mode: Mode::HBlank,
+            line_dot: 0,
+            disabled_dots: 0,
+            stat_line: false,
+            window_y_triggered: false,
+            window_line: 0,
+            screen: Screen::new(),
+            frame: Screen::new(),
+            frame_count: 0,
+        }
+    }
+
+    pub fn lcd_enabled(&self) -> bool {
+        get_bit(self.lcd_control, 7)
+    }
+
+    pub fn get_lcd_status(&self) -> u8 {
+        let mode = if self.lcd_enabled() {
+            match self.mode {
+                Mode::HBlank => 0,
+                Mode::VBlank => 1,
+                Mode::OamSearch => 2,
+                Mode::PixelTransfer => 3,
+            }
+        } else {
+            0
+        };
+        let coincidence = self.lcd_enabled() && self.line == self.line_compare;
+        0x80 | self.stat_interrupt_select | (coincidence as u8) << 2 | mode
+    }
+
+    pub fn set_lcd_status(&mut self, n: u8) {
+        self.stat_interrupt_select = n & 0x78;
+    }
+
+    pub fn set_lcd_control(&mut self, n: u8) {
+        let was_enabled = self.lcd_enabled();
+        self.lcd_control = n;
+        if was_enabled && !self.lcd_enabled() {
+            self.line = 0;
+            self.line_dot = 0;
+            self.mode = Mode::HBlank;
+            self.disabled_dots = 0;
+            self.screen = Screen::new();
+        } else if !was_enabled && self.lcd_enabled() {
+            self.mode = Mode::OamSearch;
+            self.window_y_triggered = false;
+            self.window_line = 0;
+        }
+    }
+
+    /// Places the ppu at the start of the given mode on the given line, for restoring states which
+    /// only record LY and the STAT mode.
+    pub fn set_position(&mut self, line: u8, mode: Mode) {
+        self.line = line;
+        self.mode = mode;
+        self.line_dot = match mode {
+            Mode::PixelTransfer => OAM_SEARCH_DOTS,
+            Mode::HBlank => OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS,
+            Mode::OamSearch | Mode::VBlank => 0,
+        };
+        self.disabled_dots = 0;
+        self.stat_line = self.stat_condition();
+    }
+
+    /// Advances by a single dot while the system clock is stopped, the display shows blank frames
+    /// at the usual rate.
+    pub fn tick_stopped(&mut self) {
+        self.disabled_dots += 1;
+        if self.disabled_dots == DOTS_PER_FRAME {
+            self.disabled_dots = 0;
+            self.screen = Screen::new();
+            self.finish_frame();
+        }
+    }
+
+    /// Advances the ppu by a single dot, returns the bits of any interrupts that should be
+    /// requested.
+    pub fn tick(&mut self) -> u8 {
+        if !self.lcd_enabled() {
+            self.disabled_dots += 1;
+            if self.disabled_dots == DOTS_PER_FRAME {
+                self.disabled_dots = 0;
+                self.finish_frame();
+            }
+            return 0;
+        }
+
+        let mut interrupts = 0;
+
+        self.line_dot += 1;
+        if self.line_dot == DOTS_PER_LINE {
+            self.line_dot = 0;
+            self.line = (self.line + 1) % LINES_PER_FRAME;
+            if self.line == VERTICAL_SCREEN_PIXELS {
+                self.mode = Mode::VBlank;
+                self.finish_frame();
+                interrupts = set_bit(interrupts, VBLANK_INTERRUPT, true);
+            } else if self.line < VERTICAL_SCREEN_PIXELS {
+                self.mode = Mode::OamSearch;
+            }
+
+            if self.line == 0 {
+                self.window_y_triggered = false;
+                self.window_line = 0;
+            }
+        } else if self.mode == Mode::OamSearch && self.line_dot == OAM_SEARCH_DOTS {
+            self.mode = Mode::PixelTransfer;
+        } else if self.mode == Mode::PixelTransfer &&
+                  self.line_dot == OAM_SEARCH_DOTS + PIXEL_TRANSFER_DOTS {
+            self.render_line();
+            self.mode = Mode::HBlank;
+        }
+
+        let stat_line = self.stat_condition();
+        if stat_line && !self.stat_line {
+            interrupts = set_bit(interrupts, LCD_STAT_INTERRUPT, true);
+        }
+        self.stat_line = stat_line;
+
+        interrupts
+    }
+
+    fn stat_condition(&self) -> bool {
+        let select = self.stat_interrupt_select;
+        (get_bit(select, 6) && self.line == self.line_compare) ||
+        (get_bit(select, 5) && self.mode == Mode::OamSearch) ||
+        (get_bit(select, 4) && self.mode == Mode::VBlank) ||
+        (get_bit(select, 3) && self.mode == Mode::HBlank)
+    }
+
+    fn finish_frame(&mut self) {
+        self.frame = self.screen.clone();
+        self.frame_count += 1;
+    }
+
+    fn render_line(&mut self) {
+        let y = self.line;
+
+        // The color numbers of the background before palettes are applied, needed for the sprite
+        // BG-over-OBJ priority.
+        let mut bg_colors = [0; HORIZONTAL_SCREEN_PIXELS as usize];
+        if get_bit(self.lcd_control, 0) {
+            let map_base = if get_bit(self.lcd_control, 3) { 0x400 } else { 0x0 };
+            // The background is a 256x256 pixel map which wraps around in both directions.
+            let bg_y = y.wrapping_add(self.scroll_y);
+            for x in 0..HORIZONTAL_SCREEN_PIXELS {
+                let bg_x = x.wrapping_add(self.scroll_x);
+                let tile = self.bg_map_data[map_base + (bg_y / 8) as usize * 32 +
+                                            (bg_x / 8) as usize];
+                bg_colors[x as usize] =
+                    self.tile_color(self.tile_data_index(tile), bg_y % 8, bg_x % 8);
+            }
+        }
+
+        if self.line == self.window_y {
+            self.window_y_triggered = true;
+        }
+
+        // On DMG, clearing LCDC bit 0 disables the window as well as the background.
+        if get_bit(self.lcd_control, 0) && get_bit(self.lcd_control, 5) &&
+           self.window_y_triggered && self.window_x < HORIZONTAL_SCREEN_PIXELS + 7 {
+            self.render_window(&mut bg_colors);
+        }
+
+        for x in 0..HORIZONTAL_SCREEN_PIXELS {
+            // With the background disabled, DMG shows white rather than BGP color 0.
+            let pixel = if get_bit(self.lcd_control, 0) {
+                palette_pixel(self.bg_palette, bg_colors[x as usize])
+            } else {
+                Pixel::White
+            };
+            self.screen.set_pixel(x, y, pixel);
+        }
+
+        if get_bit(self.lcd_control, 1) {
+            self.render_sprites(&bg_colors);
+        }
+    }
+
+    fn render_window(&mut self, bg_colors: &mut [u8]) {
+        let map_base = if get_bit(self.lcd_control, 6) { 0x400 } else { 0x0 };
+        let window_y = self.window_line;
+
+        // WX is offset by 7, so values below 7 start the window partially off screen.
+        for x in self.window_x.saturating_sub(7)..HORIZONTAL_SCREEN_PIXELS {
+            let window_x = x + 7 - self.window_x;
+            let tile = self.bg_map_data[map_base + (window_y / 8) as usize * 32 +
+                                        (window_x / 8) as usize];
+            bg_colors[x as usize] =
+                self.tile_color(self.tile_data_index(tile), window_y % 8, window_x % 8);
+        }
+
+        self.window_line += 1;
+    }
+
+    fn render_sprites(&mut self, bg_colors: &[u8]) {
+        let y = self.line;
+        let height = if get_bit(self.lcd_control, 2) { 16 } else { 8 };
+
+        // Only the first 10 sprites in OAM order which overlap this line are drawn.
+        let mut sprites = Vec::with_capacity(MAX_SPRITES_PER_LINE);
+        for sprite in 0..40 {
+            let sprite_y = self.sprite_attribute_data[sprite * 4] as i16 - 16;
+            if y as i16 >= sprite_y && (y as i16) < sprite_y + height {
+                sprites.push(sprite);
+                if sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+
+        // On DMG the sprite with the smallest X coordinate has priority, falling back to OAM order
+        // on ties.
+        sprites.sort_by_key(|&sprite| (self.sprite_attribute_data[sprite * 4 + 1], sprite));
+
+        // The highest priority non-transparent sprite pixel wins even when it is then hidden behind
+        // the background, so track which pixels have been claimed.
+        let mut drawn = [false; HORIZONTAL_SCREEN_PIXELS as usize];
+
+        for &sprite in &sprites {
+            let sprite_y = self.sprite_attribute_data[sprite * 4] as i16 - 16;
+            let sprite_x = self.sprite_attribute_data[sprite * 4 + 1] as i16 - 8;
+            let tile = self.sprite_attribute_data[sprite * 4 + 2];
+            let flags = self.sprite_attribute_data[sprite * 4 + 3];
+
+            let behind_bg = get_bit(flags, 7);
+            let palette = if get_bit(flags, 4) {
+                self.object_palette1
+            } else {
+                self.object_palette0
+            };
+
+            let mut row = (y as i16 - sprite_y) as u8;
+            if get_bit(flags, 6) {
+                row = height as u8 - 1 - row;
+            }
+            let tile = if height == 16 {
+                (tile & 0xfe) as usize + row as usize / 8
+            } else {
+                tile as usize
+            };
+
+            for column in 0..8 {
+                let x = sprite_x + column as i16;
+                if x < 0 || x >= HORIZONTAL_SCREEN_PIXELS as i16 || drawn[x as usize] {
+                    continue;
+                }
+
+                let tile_column = if get_bit(flags, 5) { 7 - column } else { column };
+                let color = self.tile_color(tile, row % 8, tile_column);
+                // Color 0 is always transparent for sprites
+                if color == 0 {
+                    continue;
+                }
+
+                drawn[x as usize] = true;
+                if !behind_bg || bg_colors[x as usize] == 0 {
+                    self.screen.set_pixel(x as u8, y, palette_pixel(palette, color));
+                }
+            }
+        }
+    }
+
+    // Background and window tile numbers either index tiles from 0x8000 unsigned or from 0x9000
+    // signed, depending on LCDC bit 4. Returns the tile index into character ram.
+    fn tile_data_index(&self, tile: u8) -> usize {
+        if get_bit(self.lcd_control, 4) {
+            tile as usize
+        } else {
+            (0x100 + tile as i8 as i16) as usize
+        }
+    }
+
+    // Returns the color number of a pixel in a tile in character ram, tile rows and columns are
+    // numbered from the top left.
+    fn tile_color(&self, tile: usize, row: u8, column: u8) -> u8 {
+        let b1 = self.character_ram[tile * 16 + row as usize * 2];
+        let b2 = self.character_ram[tile * 16 + row as usize * 2 + 1];
+        let bit = 7 - column;
+        (get_bit(b2, bit) as u8) << 1 | get_bit(b1, bit) as u8
+    }
+}
+
+// Maps a color number through one of the palette registers
+fn palette_pixel(palette: u8, color: u8) -> Pixel {
+    match (palette >> (color * 2)) & 0x3 {
+        0 => Pixel::White,
+        1 => Pixel::LightGray,
+        2 => Pixel::DarkGray,
+        _ => Pixel::Black,
+    }
+}
+
+impl SaveState for Ppu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.character_ram);
+        writer.write_bytes(&self.bg_map_data);
+        writer.write_bytes(&self.sprite_attribute_data);
+        writer.write_u8(self.lcd_control);
+        writer.write_u8(self.stat_interrupt_select);
+        writer.write_u8(self.scroll_y);
+        writer.write_u8(self.scroll_x);
+        writer.write_u8(self.line);
+        writer.write_u8(self.line_compare);
+        writer.write_u8(self.bg_palette);
+        writer.write_u8(self.object_palette0);
+        writer.write_u8(self.object_palette1);
+        writer.write_u8(self.window_y);
+        writer.write_u8(self.window_x);
+        writer.write_u8(match self.mode {
+                            Mode::HBlank => 0,
+                            Mode::VBlank => 1,
+                            Mode::OamSearch => 2,
+                            Mode::PixelTransfer => 3,
+                        });
+        writer.write_u16(self.line_dot);
+        writer.write_u32(self.disabled_dots);
+        writer.write_bool(self.stat_line);
+        writer.write_bool(self.window_y_triggered);
+        writer.write_u8(self.window_line);
+        self.screen.save_state(writer);
+        self.frame.save_state(writer);
+        writer.write_u64(self.frame_count);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        reader.read_bytes_into(&mut self.character_ram)?;
+        reader.read_bytes_into(&mut self.bg_map_data)?;
+        reader.read_bytes_into(&mut self.sprite_attribute_data)?;
+        self.lcd_control = reader.read_u8()?;
+        self.stat_interrupt_select = reader.read_u8()?;
+        self.scroll_y = reader.read_u8()?;
+        self.scroll_x = reader.read_u8()?;
+        self.line = reader.read_u8()?;
+        self.line_compare = reader.read_u8()?;
+        self.bg_palette = reader.read_u8()?;
+        self.object_palette0 = reader.read_u8()?;
+        self.object_palette1 = reader.read_u8()?;
+        self.window_y = reader.read_u8()?;
+        self.window_x = reader.read_u8()?;
+        self.mode = match reader.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::OamSearch,
+            _ => Mode::PixelTransfer,
+        };
+        self.line_dot = reader.read_u16()?;
+        self.disabled_dots = reader.read_u32()?;
+        self.stat_line = reader.read_bool()?;
+        self.window_y_triggered = reader.read_bool()?;
+        self.window_line = reader.read_u8()?;
+        self.screen.load_state(reader)?;
+        self.frame.load_state(reader)?;
+        self.frame_count = reader.read_u64()?;
+        Ok(())
+    }
+}
@@ -1,3 +1,6 @@
+use util::*;
+use state::*;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Pixel {
     Black,
@@ -9,6 +12,7 @@ pub enum Pixel {
 pub const HORIZONTAL_SCREEN_PIXELS: u8 = 160;
 pub const VERTICAL_SCREEN_PIXELS: u8 = 144;
 
+#[derive(Clone)]
 pub struct Screen([Pixel; HORIZONTAL_SCREEN_PIXELS as usize * VERTICAL_SCREEN_PIXELS as usize]);
 
 impl Screen {
@@ -24,3 +28,34 @@ impl Screen {
         self.0[y as usize * HORIZONTAL_SCREEN_PIXELS as usize + x as usize] = p;
     }
 }
+
+impl SaveState for Screen {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let pixels: Vec<u8> = self.0
+            .iter()
+            .map(|&p| match p {
+                     Pixel::White => 0,
+                     Pixel::LightGray => 1,
+                     Pixel::DarkGray => 2,
+                     Pixel::Black => 3,
+                 })
+            .collect();
+        writer.write_bytes(&pixels);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        let pixels = reader.read_bytes()?;
+        if pixels.len() != self.0.len() {
+            return Err("save state screen size mismatch".into());
+        }
+        for (p, &v) in self.0.iter_mut().zip(pixels) {
+            *p = match v {
+                0 => Pixel::White,
+                1 => Pixel::LightGray,
+                2 => Pixel::DarkGray,
+                _ => Pixel::Black,
+            };
+        }
+        Ok(())
+    }
+}
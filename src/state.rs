@@ -0,0 +1,139 @@
+use util::*;
+
+pub const STATE_MAGIC: &[u8; 4] = b"RSGB";
+/// Bumped whenever the fields of a section or the header change, states of other versions are
+/// rejected. New sections can be added without a bump, older readers skip them.
+pub const STATE_VERSION: u32 = 2;
+
+/// Types whose state can be saved to and restored from a save state section.
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()>;
+}
+
+/// Writes little endian save state data.
+pub struct StateWriter {
+    pub data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.data.push(v as u8);
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Writes a length prefixed byte array.
+    pub fn write_bytes(&mut self, v: &[u8]) {
+        self.write_u32(v.len() as u32);
+        self.data.extend_from_slice(v);
+    }
+
+    /// Writes a section with the given tag, its length prefixed payload is written by f.
+    pub fn write_section<F: FnOnce(&mut StateWriter)>(&mut self, tag: &[u8; 4], f: F) {
+        let mut section = StateWriter::new();
+        f(&mut section);
+        self.data.extend_from_slice(tag);
+        self.write_bytes(&section.data);
+    }
+}
+
+/// Reads little endian save state data.
+pub struct StateReader<'a> {
+    pub data: &'a [u8],
+    pub position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data,
+            position: 0,
+        }
+    }
+
+    /// Whether there is unread data left.
+    pub fn has_remaining(&self) -> bool {
+        self.position < self.data.len()
+    }
+
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.position < len {
+            return Err("save state truncated".into());
+        }
+        let slice = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_slice(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.read_slice(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_slice(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_slice(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Reads a length prefixed byte array.
+    pub fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_slice(len)
+    }
+
+    /// Reads a length prefixed byte array into a fixed size buffer, which must match its length.
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<()> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(format!("save state array size mismatch, expected {} bytes but found {}",
+                               buffer.len(),
+                               bytes.len())
+                               .into());
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Reads the next section, returning its tag and a reader over its payload.
+    pub fn read_section(&mut self) -> Result<([u8; 4], StateReader<'a>)> {
+        let mut tag = [0; 4];
+        tag.copy_from_slice(self.read_slice(4)?);
+        let payload = self.read_bytes()?;
+        Ok((tag, StateReader::new(payload)))
+    }
+}
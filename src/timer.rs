@@ -0,0 +1,227 @@
+use util::*;
+use cpu::*;
+use state::*;
+
+pub struct Timer {
+    /// The internal 16 bit divider incremented every clock, DIV (0xff04) is its upper byte.
+    pub divider: u16,
+    /// TIMA (0xff05)
+    pub counter: u8,
+    /// TMA (0xff06)
+    pub modulo: u8,
+    /// TAC (0xff07)
+    pub control: u8,
+
+    /// Set when TIMA overflowed during the last machine cycle, TIMA reads as 0 until it is reloaded
+    /// from TMA on the next cycle.
+    pub overflow: bool,
+    /// Set during the machine cycle in which TIMA is reloaded from TMA, writes to TIMA are ignored
+    /// and writes to TMA also go to TIMA.
+    pub reloading: bool,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0x0,
+            counter: 0x0,
+            modulo: 0x0,
+            control: 0x0,
+            overflow: false,
+            reloading: false,
+        }
+    }
+
+    /// Advances the timer by a single machine cycle, returns the bits of any interrupts that should
+    /// be requested.
+    pub fn tick(&mut self) -> u8 {
+        let mut interrupts = 0;
+
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.counter = self.modulo;
+            self.reloading = true;
+            interrupts = set_bit(interrupts, TIMER_INTERRUPT, true);
+        }
+
+        let old_signal = self.signal();
+        self.divider = self.divider.wrapping_add(4);
+        if old_signal && !self.signal() {
+            self.increment();
+        }
+
+        interrupts
+    }
+
+    pub fn get_divider(&self) -> u8 {
+        high_byte(self.divider)
+    }
+
+    /// Any write to DIV resets the whole internal divider, which can cause a falling edge on the
+    /// selected bit and so a spurious TIMA increment.
+    pub fn reset_divider(&mut self) {
+        let old_signal = self.signal();
+        self.divider = 0;
+        if old_signal {
+            self.increment();
+        }
+    }
+
+    pub fn set_counter(&mut self, n: u8) {
+        if !self.reloading {
+            self.counter = n;
+            // Writing TIMA in the cycle after an overflow cancels the pending reload and
+            // interrupt.
+            self.overflow = false;
+        }
+    }
+
+    pub fn set_modulo(&mut self, n: u8) {
+        self.modulo = n;
+        if self.reloading {
+            self.counter = n;
+        }
+    }
+
+    pub fn get_control(&self) -> u8 {
+        0xf8 | self.control
+    }
+
+    /// Disabling the timer or changing the selected divider bit while the old signal was high is
+    /// seen as a falling edge and increments TIMA.
+    pub fn set_control(&mut self, n: u8) {
+        let old_signal = self.signal();
+        self.control = n & 0x07;
+        if old_signal && !self.signal() {
+            self.increment();
+        }
+    }
+
+    // The timer enable bit ANDed with the divider bit selected by TAC, TIMA increments on each
+    // falling edge of this signal.
+    fn signal(&self) -> bool {
+        let bit = match self.control & 0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        get_bit(self.control, 2) && get_bit(self.divider, bit)
+    }
+
+    fn increment(&mut self) {
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = counter;
+        if overflow {
+            self.overflow = true;
+        }
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.divider);
+        writer.write_u8(self.counter);
+        writer.write_u8(self.modulo);
+        writer.write_u8(self.control);
+        writer.write_bool(self.overflow);
+        writer.write_bool(self.reloading);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<()> {
+        self.divider = reader.read_u16()?;
+        self.counter = reader.read_u8()?;
+        self.modulo = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.overflow = reader.read_bool()?;
+        self.reloading = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMER_INTERRUPT_BIT: u8 = 1 << TIMER_INTERRUPT;
+
+    // Returns a timer counting every 4 machine cycles, just after the cycle in which TIMA
+    // overflowed.
+    fn overflowed_timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.control = 0x05;
+        timer.counter = 0xff;
+        timer.modulo = 0x42;
+        while !timer.overflow {
+            assert_eq!(timer.tick(), 0);
+        }
+        timer
+    }
+
+    #[test]
+    fn reload_is_delayed_by_one_cycle() {
+        let mut timer = overflowed_timer();
+        assert_eq!(timer.counter, 0x00);
+        assert_eq!(timer.tick(), TIMER_INTERRUPT_BIT);
+        assert_eq!(timer.counter, 0x42);
+    }
+
+    #[test]
+    fn tima_write_in_overflow_cycle_cancels_reload() {
+        let mut timer = overflowed_timer();
+        timer.set_counter(0x10);
+        assert_eq!(timer.tick(), 0);
+        assert_eq!(timer.counter, 0x10);
+    }
+
+    #[test]
+    fn writes_during_reload_cycle() {
+        let mut timer = overflowed_timer();
+        timer.tick();
+        // TIMA writes are ignored and TMA writes also go to TIMA.
+        timer.set_counter(0x10);
+        assert_eq!(timer.counter, 0x42);
+        timer.set_modulo(0x99);
+        assert_eq!(timer.counter, 0x99);
+
+        // Once the reload cycle is over TMA writes only change TMA.
+        timer.tick();
+        timer.set_modulo(0x55);
+        assert_eq!(timer.counter, 0x99);
+    }
+
+    #[test]
+    fn div_write_glitch_increment() {
+        let mut timer = Timer::new();
+        timer.control = 0x05;
+        timer.divider = 0x0008;
+        timer.reset_divider();
+        assert_eq!(timer.counter, 1);
+
+        // No increment when the selected bit is low.
+        timer.divider = 0x0004;
+        timer.reset_divider();
+        assert_eq!(timer.counter, 1);
+    }
+
+    #[test]
+    fn tac_change_glitch_increment() {
+        let mut timer = Timer::new();
+        timer.control = 0x05;
+        timer.divider = 0x0008;
+
+        // Selecting bit 9, which is low.
+        timer.set_control(0x04);
+        assert_eq!(timer.counter, 1);
+
+        // Disabling the timer while the selected bit is high.
+        timer.set_control(0x05);
+        timer.set_control(0x01);
+        assert_eq!(timer.counter, 2);
+
+        // Enabling it again does not increment.
+        timer.set_control(0x05);
+        assert_eq!(timer.counter, 2);
+    }
+}
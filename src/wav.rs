@@ -0,0 +1,86 @@
+use std::io::Write;
+
+use util::*;
+
+/// Writes 16 bit PCM samples as a WAV file, with the samples of each channel interleaved.
+pub fn write_wav<W: Write>(writer: &mut W,
+                           sample_rate: u32,
+                           channels: u16,
+                           samples: &[i16])
+                           -> Result<()> {
+    let data_len = samples.len() as u32 * 2;
+    let block_align = channels * 2;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&channels.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    writer.write_all(&header)?;
+
+    let mut data = Vec::with_capacity(data_len as usize);
+    for &sample in samples {
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    #[test]
+    fn stereo_header_and_layout() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 44100, 2, &[1, -1, 0x1234, -0x8000]).unwrap();
+
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(read_u32(&wav, 4), wav.len() as u32 - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(read_u32(&wav, 16), 16);
+        assert_eq!(read_u16(&wav, 20), 1);
+        assert_eq!(read_u16(&wav, 22), 2);
+        assert_eq!(read_u32(&wav, 24), 44100);
+        assert_eq!(read_u32(&wav, 28), 44100 * 4);
+        assert_eq!(read_u16(&wav, 32), 4);
+        assert_eq!(read_u16(&wav, 34), 16);
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(read_u32(&wav, 40), 8);
+        assert_eq!(&wav[44..], &[0x01, 0x00, 0xff, 0xff, 0x34, 0x12, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn mono_header() {
+        let mut wav = Vec::new();
+        write_wav(&mut wav, 8000, 1, &[0; 3]).unwrap();
+
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(read_u32(&wav, 4), 36 + 6);
+        assert_eq!(read_u16(&wav, 22), 1);
+        assert_eq!(read_u32(&wav, 28), 8000 * 2);
+        assert_eq!(read_u16(&wav, 32), 2);
+        assert_eq!(read_u32(&wav, 40), 6);
+    }
+}